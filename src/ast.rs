@@ -24,23 +24,46 @@ pub enum PatternDecl {
     ConstFloat(f64),
     ConstChar(char),
     ConstStr(String),
+    ConstAtom(String),
+    ConstBool(bool),
+    Nil,
     Cons(Box<Pattern>, Box<Pattern>),
     EmptyList,
     Tuple(Vec<Box<Pattern>>),
     Record(Vec<(String, Option<Box<Pattern>>)>),
     Array(Vec<Box<Pattern>>),
+    /// `p1 | p2`, every alternative binds the same names.
+    Or(Vec<Box<Pattern>>),
+    /// `name @ pattern`
+    Bind(String, Box<Pattern>),
+    /// `lo..hi` or `lo..=hi` with literal bounds.
+    Range(Box<Pattern>, Box<Pattern>, bool),
     Pass,
-    Rest,
+    /// `..` or `..name` inside an array pattern.
+    Rest(Option<String>),
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Arg {
     /// `function foo (x,y / *Ident*/ )`
     Ident(bool, String),
-    /// `function foo ( {x,y} /* Record */ )`
-    Record(Vec<String>),
-    /// `function foo ( [x,y] /* Array */ )`
-    Array(Vec<String>),
+    /// `function foo ( {x,y: [a, b]} /* Record */ )`
+    Record(Vec<(String, Option<Box<Pattern>>)>),
+    /// `function foo ( [x,{y}] /* Array */ )`
+    Array(Vec<Box<Pattern>>),
+    /// `function foo (x = 10)`
+    Default(bool, String, Box<Expr>),
+    /// `function foo (...rest)`
+    Rest(String),
+}
+
+/// An entry of an object literal or of a record assignment target.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ObjectField {
+    /// `name: value`, or `name` alone for `name: name`.
+    Field(String, Option<Box<Expr>>),
+    /// `...source`, copies the fields of `source`.
+    Spread(Box<Expr>),
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -49,6 +72,69 @@ pub struct Pattern {
     pub pos: Position,
 }
 
+impl Pattern {
+    /// Names bound by this pattern, in source order.
+    pub fn bindings(&self) -> Vec<String> {
+        let mut names = vec![];
+        self.collect_bindings(&mut names);
+        names
+    }
+
+    fn collect_bindings(&self, names: &mut Vec<String>) {
+        match &self.decl {
+            PatternDecl::Ident(name) | PatternDecl::Rest(Some(name)) => names.push(name.clone()),
+            PatternDecl::Bind(name, pat) => {
+                names.push(name.clone());
+                pat.collect_bindings(names);
+            }
+            PatternDecl::Cons(head, tail) => {
+                head.collect_bindings(names);
+                tail.collect_bindings(names);
+            }
+            PatternDecl::Tuple(pats) | PatternDecl::Array(pats) => {
+                for pat in pats.iter() {
+                    pat.collect_bindings(names);
+                }
+            }
+            PatternDecl::Or(alts) => alts[0].collect_bindings(names),
+            PatternDecl::Record(fields) => record_bindings(fields, names),
+            _ => (),
+        }
+    }
+}
+
+fn record_bindings(fields: &[(String, Option<Box<Pattern>>)], names: &mut Vec<String>) {
+    for (name, pat) in fields.iter() {
+        match pat {
+            Some(pat) => pat.collect_bindings(names),
+            None => names.push(name.clone()),
+        }
+    }
+}
+
+impl Arg {
+    fn collect_bindings(&self, names: &mut Vec<String>) {
+        match self {
+            Arg::Ident(_, name) | Arg::Default(_, name, _) | Arg::Rest(name) => {
+                names.push(name.clone())
+            }
+            Arg::Record(fields) => record_bindings(fields, names),
+            Arg::Array(pats) => {
+                for pat in pats.iter() {
+                    pat.collect_bindings(names);
+                }
+            }
+        }
+    }
+}
+
+/// `pattern [when guard] -> body` of a `match`, `receive` or `catch`.
+pub type MatchArm = (Box<Pattern>, Option<Box<Expr>>, Box<Expr>);
+
+/// `for pattern in iter` of a comprehension with the `if` conditions
+/// following it.
+pub type ForClause = (Box<Pattern>, Box<Expr>, Vec<Box<Expr>>);
+
 #[derive(Clone, PartialEq)]
 pub struct Expr {
     pub pos: Position,
@@ -58,32 +144,79 @@ pub struct Expr {
 #[derive(Clone, Debug, PartialEq)]
 pub enum ExprKind {
     Assign(Box<Expr>, Box<Expr>),
+    /// `lhs op= rhs`, `op` is the binary operator such as `"+"`.
+    CompoundAssign(String, Box<Expr>, Box<Expr>),
     BinOp(Box<Expr>, String, Box<Expr>),
     Unop(String, Box<Expr>),
     Access(Box<Expr>, String),
     Ident(String),
     Function(Option<String>, Vec<Arg>, Box<Expr>),
+    /// `function* name(params) block`, calling it returns an iterator that
+    /// runs the body up to each `yield`.
+    Generator(Option<String>, Vec<Arg>, Box<Expr>),
+    /// `async function name(params) block`, calling it runs the body in a
+    /// new process and returns a task to `await`.
+    AsyncFunction(Option<String>, Vec<Arg>, Box<Expr>),
     Lambda(Vec<Arg>, Box<Expr>),
-    Match(Box<Expr>, Vec<(Box<Pattern>, Option<Box<Expr>>, Box<Expr>)>),
+    Match(Box<Expr>, Vec<MatchArm>),
+    /// `receive { pattern [when guard] -> expr, ..., after ms -> expr }`
+    Receive(Vec<MatchArm>, Option<(Box<Expr>, Box<Expr>)>),
     If(Box<Expr>, Box<Expr>, Option<Box<Expr>>),
+    /// `if let pattern = value then else`
+    IfLet(Box<Pattern>, Box<Expr>, Box<Expr>, Option<Box<Expr>>),
     ConstInt(i64),
     ConstChar(char),
     ConstStr(String),
+    ConstAtom(String),
     New(Box<Expr>),
     ConstFloat(f64),
     Object(Vec<(Box<Expr>, Box<Expr>)>),
     Var(bool, String, Option<Box<Expr>>),
     Let(bool, Box<Pattern>, Box<Expr>),
     While(Box<Expr>, Box<Expr>),
+    /// `while let pattern = value block`
+    WhileLet(Box<Pattern>, Box<Expr>, Box<Expr>),
+    For(Box<Pattern>, Box<Expr>, Box<Expr>),
+    /// `start..end`, inclusive when written as `start..=end`.
+    Range(Box<Expr>, Box<Expr>, bool),
     Block(Vec<Box<Expr>>),
     Return(Option<Box<Expr>>),
     Call(Box<Expr>, Vec<Box<Expr>>),
     Nil,
     Throw(Box<Expr>),
+    /// `try block catch pattern [when guard] block ... finally block`
+    Try(Box<Expr>, Vec<MatchArm>, Option<Box<Expr>>),
+    Break,
+    Continue,
+    /// `defer expr`, runs `expr` when the enclosing block is left.
+    Defer(Box<Expr>),
+    /// `yield value`
+    Yield(Box<Expr>),
+    /// `yield* iterable`, yields every value of `iterable`.
+    YieldFrom(Box<Expr>),
+    /// `await task`, blocks the current process until `task` finished.
+    Await(Box<Expr>),
+    /// The block a macro call expanded to, errors in it are reported at the
+    /// call.
+    Expansion(String, Box<Expr>),
+    /// `parallel { a = exprA; b = exprB }`, evaluates every expression in
+    /// its own process and binds the results once all of them finished.
+    Parallel(Vec<(Box<Pattern>, Box<Expr>)>),
     ConstBool(bool),
-    NewObject(Vec<(String, Option<Box<Expr>>)>),
+    NewObject(Vec<ObjectField>),
     Array(Vec<Box<Expr>>),
+    /// `[expr for pattern in iter if cond ...]`
+    Comprehension(Box<Expr>, Vec<ForClause>),
+    /// `...value` in call arguments and array literals.
+    Spread(Box<Expr>),
+    /// The trailing `x: 1, y: 2` arguments of a call, passed as one record.
+    KeywordArgs(Vec<(String, Box<Expr>)>),
     ArrayIndex(Box<Expr>, Box<Expr>),
+    /// A whole `a?.b(c)?[d]` chain, nil as soon as one of its `SafeNav`s
+    /// sees nil.
+    Optional(Box<Expr>),
+    /// The `a` in `a?.b` or `a?[i]`.
+    SafeNav(Box<Expr>),
     Class(String, Option<Box<Expr>>, Vec<Box<Expr>>),
     Tuple(Vec<Box<Expr>>),
     This,
@@ -106,6 +239,178 @@ impl Expr {
         false
     }
 
+    /// Names bound anywhere inside this expression by `var`, patterns and
+    /// parameters, in source order.
+    pub fn bindings(&self) -> Vec<String> {
+        let mut names = vec![];
+        self.collect_bindings(&mut names);
+        names
+    }
+
+    fn collect_bindings(&self, names: &mut Vec<String>) {
+        let arms = |arms: &[MatchArm], names: &mut Vec<String>| {
+            for (pat, guard, body) in arms.iter() {
+                pat.collect_bindings(names);
+                if let Some(guard) = guard {
+                    guard.collect_bindings(names);
+                }
+                body.collect_bindings(names);
+            }
+        };
+        match &self.expr {
+            ExprKind::Function(_, params, body)
+            | ExprKind::Generator(_, params, body)
+            | ExprKind::AsyncFunction(_, params, body)
+            | ExprKind::Lambda(params, body) => {
+                for param in params.iter() {
+                    param.collect_bindings(names);
+                    if let Arg::Default(_, _, value) = param {
+                        value.collect_bindings(names);
+                    }
+                }
+                body.collect_bindings(names);
+            }
+            ExprKind::Var(_, name, value) => {
+                names.push(name.clone());
+                if let Some(value) = value {
+                    value.collect_bindings(names);
+                }
+            }
+            ExprKind::Let(_, pat, value) => {
+                pat.collect_bindings(names);
+                value.collect_bindings(names);
+            }
+            ExprKind::For(pat, value, body) | ExprKind::WhileLet(pat, value, body) => {
+                pat.collect_bindings(names);
+                value.collect_bindings(names);
+                body.collect_bindings(names);
+            }
+            ExprKind::IfLet(pat, value, then, otherwise) => {
+                pat.collect_bindings(names);
+                value.collect_bindings(names);
+                then.collect_bindings(names);
+                if let Some(otherwise) = otherwise {
+                    otherwise.collect_bindings(names);
+                }
+            }
+            ExprKind::Match(value, list) => {
+                value.collect_bindings(names);
+                arms(list, names);
+            }
+            ExprKind::Receive(list, after) => {
+                arms(list, names);
+                if let Some((timeout, body)) = after {
+                    timeout.collect_bindings(names);
+                    body.collect_bindings(names);
+                }
+            }
+            ExprKind::Try(body, catches, finally) => {
+                body.collect_bindings(names);
+                arms(catches, names);
+                if let Some(finally) = finally {
+                    finally.collect_bindings(names);
+                }
+            }
+            ExprKind::Parallel(bindings) => {
+                for (pat, value) in bindings.iter() {
+                    pat.collect_bindings(names);
+                    value.collect_bindings(names);
+                }
+            }
+            ExprKind::Comprehension(value, clauses) => {
+                for (pat, iter, conds) in clauses.iter() {
+                    pat.collect_bindings(names);
+                    iter.collect_bindings(names);
+                    for cond in conds.iter() {
+                        cond.collect_bindings(names);
+                    }
+                }
+                value.collect_bindings(names);
+            }
+            ExprKind::Assign(a, b)
+            | ExprKind::CompoundAssign(_, a, b)
+            | ExprKind::BinOp(a, _, b)
+            | ExprKind::While(a, b)
+            | ExprKind::Range(a, b, _)
+            | ExprKind::ArrayIndex(a, b) => {
+                a.collect_bindings(names);
+                b.collect_bindings(names);
+            }
+            ExprKind::If(cond, then, otherwise) => {
+                cond.collect_bindings(names);
+                then.collect_bindings(names);
+                if let Some(otherwise) = otherwise {
+                    otherwise.collect_bindings(names);
+                }
+            }
+            ExprKind::Unop(_, e)
+            | ExprKind::Access(e, _)
+            | ExprKind::New(e)
+            | ExprKind::Throw(e)
+            | ExprKind::Return(Some(e))
+            | ExprKind::Defer(e)
+            | ExprKind::Yield(e)
+            | ExprKind::YieldFrom(e)
+            | ExprKind::Await(e)
+            | ExprKind::Expansion(_, e)
+            | ExprKind::Spread(e)
+            | ExprKind::Optional(e)
+            | ExprKind::SafeNav(e) => e.collect_bindings(names),
+            ExprKind::Block(items) | ExprKind::Array(items) | ExprKind::Tuple(items) => {
+                for item in items.iter() {
+                    item.collect_bindings(names);
+                }
+            }
+            ExprKind::Call(callee, args) => {
+                callee.collect_bindings(names);
+                for arg in args.iter() {
+                    arg.collect_bindings(names);
+                }
+            }
+            ExprKind::Object(fields) => {
+                for (key, value) in fields.iter() {
+                    key.collect_bindings(names);
+                    value.collect_bindings(names);
+                }
+            }
+            ExprKind::NewObject(fields) => {
+                for field in fields.iter() {
+                    match field {
+                        ObjectField::Field(_, Some(value)) | ObjectField::Spread(value) => {
+                            value.collect_bindings(names)
+                        }
+                        ObjectField::Field(_, None) => (),
+                    }
+                }
+            }
+            ExprKind::KeywordArgs(fields) => {
+                for (_, value) in fields.iter() {
+                    value.collect_bindings(names);
+                }
+            }
+            ExprKind::Class(_, proto, items) => {
+                if let Some(proto) = proto {
+                    proto.collect_bindings(names);
+                }
+                for item in items.iter() {
+                    item.collect_bindings(names);
+                }
+            }
+            ExprKind::Ident(_)
+            | ExprKind::ConstInt(_)
+            | ExprKind::ConstChar(_)
+            | ExprKind::ConstStr(_)
+            | ExprKind::ConstAtom(_)
+            | ExprKind::ConstFloat(_)
+            | ExprKind::ConstBool(_)
+            | ExprKind::Return(None)
+            | ExprKind::Nil
+            | ExprKind::Break
+            | ExprKind::Continue
+            | ExprKind::This => (),
+        }
+    }
+
     pub fn is_binop_cmp(&self) -> bool {
         if let ExprKind::BinOp(_, ref op, _) = self.expr {
             let op: &str = op;
@@ -15,7 +15,10 @@
 *   limitations under the License.
 */
 use crate::ast::*;
+use crate::intrinsics;
 use crate::msg::*;
+use crate::parser::Parser;
+use crate::reader::Reader;
 use crate::token::Position;
 use basicblock::*;
 use cell::*;
@@ -24,7 +27,8 @@ use instruction::*;
 use module::*;
 use runtime::*;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
 use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
 use std::rc::Rc;
 use value::*;
@@ -55,6 +59,11 @@ pub struct Globals {
     pub objects: LinkedHashMap<String, Vec<i32>>,
     pub functions: Vec<(Vec<BasicBlock>, Vec<(i32, i32)>, i32, i32, String)>,
     pub table: Vec<Global>,
+    /// Parameters of the functions declared at the top level of the module,
+    /// by the name calls use, to check keyword arguments.
+    pub signatures: HashMap<String, Vec<Arg>>,
+    /// Names of the atoms used by the module, see `Context::intern_atoms`.
+    pub atoms: LinkedHashSet<String>,
 }
 
 #[derive(Clone)]
@@ -63,6 +72,37 @@ pub struct LoopControlInfo {
     pub continue_point: u16,
 }
 
+/// A `try` around the code being compiled. `return`, `break` and `continue`
+/// that leave it must drop its handler and run its `finally` block first.
+#[derive(Clone)]
+pub struct TryInfo {
+    pub finally: Option<Box<Expr>>,
+    /// Number of loops entered outside of the `try`.
+    pub loop_depth: usize,
+    /// Variables visible at the `try`, names declared inside it must not
+    /// shadow them in `finally`.
+    pub locals: LinkedHashMap<String, i32>,
+}
+
+/// The `next()` function of a generator being compiled. Registers live
+/// across a `yield` are saved in the `frame` array of the generator and
+/// restored when `next()` resumes.
+pub struct GeneratorInfo {
+    /// Register holding the generator object.
+    pub gen: u16,
+    /// Register holding the receiver the generator function was called on,
+    /// what `this` means in the body.
+    pub receiver: u16,
+    /// Register holding the frame array of the generator.
+    pub frame: u16,
+    /// Blocks where the body resumes after each `yield`, state `i + 1`
+    /// resumes in `resumes[i]`.
+    pub resumes: Vec<usize>,
+    /// Where to insert the register saves of each `yield`, as (block,
+    /// instruction index).
+    pub saves: Vec<(usize, usize)>,
+}
+
 pub struct Context {
     pub g: Rc<RefCell<Globals>>,
     pub bbs: Vec<BasicBlock>,
@@ -71,6 +111,12 @@ pub struct Context {
     pub locals: LinkedHashMap<String, i32>,
     pub labels: HashMap<String, Option<u32>>,
     loop_control_info: Vec<LoopControlInfo>,
+    try_stack: Vec<TryInfo>,
+    /// For each enclosing `ExprKind::Optional`, the nil checks of its
+    /// `SafeNav`s: (block, block to continue in, register holding `obj == nil`).
+    optional_exits: Vec<Vec<(usize, usize, u16)>>,
+    /// Set while compiling the body of a generator.
+    generator: Option<GeneratorInfo>,
     pub stack: i32,
     pub limit: i32,
     pub nenv: i32,
@@ -152,8 +198,10 @@ impl Context {
 
     pub fn scoped<R, T: FnMut(&mut Self) -> R>(&mut self, mut f: T) -> R {
         let prev = self.locals.clone();
+        let prev_immutable = self.immutable.clone();
         let ret = catch_unwind(AssertUnwindSafe(|| f(self)));
         self.locals = prev;
+        self.immutable = prev_immutable;
 
         match ret {
             Ok(v) => v,
@@ -201,7 +249,7 @@ impl Context {
                 //self.locals.insert(name, l as _);
                 return Ok(l as _);
             }
-            Access::Global(_, _, _) => unimplemented!(),
+            Access::Global(_, _, name) => Err(MsgWithPos::new(p, Msg::UnknownIdentifier(name))),
             Access::Field(obj, f) => {
                 let (gid, _) = self.global(&Global::Str(f.to_owned()));
                 let obj = self.compile(&*obj, false)?;
@@ -218,7 +266,7 @@ impl Context {
             }
             //Access::This => self.write(Opcode::SetThis),
             //Access::Array => self.write(Opcode::SetArray),
-            _ => unimplemented!(),
+            _ => Err(MsgWithPos::new(p, Msg::LvalueExpected)),
         }
     }
     pub fn access_get(&mut self, acc: Access) -> Result<u16, MsgWithPos> {
@@ -250,7 +298,7 @@ impl Context {
                 return Ok(r);
             }
             Access::This => {
-                self.write(Instruction::LoadThis(r));
+                self.load_this(r);
                 return Ok(r);
             }
             Access::Array(value, index) => {
@@ -291,35 +339,156 @@ impl Context {
 
         None
     }
-    pub fn compile_access(&mut self, e: &ExprKind) -> Access {
+    /// Applies `f` to the function declaring the variable `name`, this one
+    /// or an enclosing one.
+    fn in_scope_of<T, F>(&self, name: &str, f: F) -> Option<T>
+    where
+        F: FnOnce(&Context) -> T,
+    {
+        if self.locals.contains_key(name) {
+            return Some(f(self));
+        }
+        let mut current = self.parent;
+        while let Some(ctx) = current {
+            if ctx.locals.contains_key(name) {
+                return Some(f(&ctx));
+            }
+            current = ctx.parent;
+        }
+        None
+    }
+
+    /// Whether `name` resolves to a `let` binding, looking through
+    /// enclosing functions for captured variables.
+    pub fn is_immutable(&self, name: &str) -> bool {
+        self.in_scope_of(name, |ctx| ctx.immutable.contains(name))
+            .unwrap_or(false)
+    }
+
+    pub fn compile_access(&mut self, p: Position, e: &ExprKind) -> Result<Access, MsgWithPos> {
         match e {
             ExprKind::Ident(name) => {
                 let l = self.locals.get(name);
                 if l.is_some() {
                     let l = *l.unwrap();
-                    return Access::Stack(name.to_owned(), l);
+                    return Ok(Access::Stack(name.to_owned(), l));
                 } else {
                     if let Some(acc) = self.access_env(name) {
-                        return acc;
+                        return Ok(acc);
                     } else {
                         let (g, n) = self.global(&Global::Var(name.to_owned()));
-                        return Access::Global(g, n, name.to_owned());
+                        return Ok(Access::Global(g, n, name.to_owned()));
                     }
                 }
             }
             ExprKind::Access(e, f) => {
-                return Access::Field(e.clone(), f.to_owned());
+                return Ok(Access::Field(e.clone(), f.to_owned()));
             }
-            ExprKind::This => Access::This,
+            ExprKind::This => Ok(Access::This),
             ExprKind::ArrayIndex(ea, ei) => {
-                return Access::Array(ea.clone(), ei.clone());
+                return Ok(Access::Array(ea.clone(), ei.clone()));
             }
-            _ => unimplemented!(),
+            _ => Err(MsgWithPos::new(p, Msg::LvalueExpected)),
+        }
+    }
+
+    /// `lhs op= rhs`, the object and index of `lhs` are evaluated once.
+    pub fn compile_compound_assign(
+        &mut self,
+        p: Position,
+        op: &str,
+        lhs: &Expr,
+        rhs: &Expr,
+    ) -> Result<u16, MsgWithPos> {
+        let op = binop(op).unwrap();
+        match &lhs.expr {
+            ExprKind::Ident(name) => {
+                if self.is_immutable(name) {
+                    return Err(MsgWithPos::new(p, Msg::LetReassigned));
+                }
+                let acc = self.compile_access(lhs.pos, &lhs.expr)?;
+                let value = self.access_get(acc.clone())?;
+                let r = self.compile(rhs, false)?;
+                let result = self.new_reg();
+                self.write(Instruction::Binary(op, result, value, r));
+                self.access_set(p, acc, result)
+            }
+            ExprKind::Access(object, field) => {
+                let object = self.compile(object, false)?;
+                let (gid, _) = self.global(&Global::Str(field.to_owned()));
+                let value = self.new_reg();
+                self.write(Instruction::LoadById(value, object, gid as _));
+                let r = self.compile(rhs, false)?;
+                let result = self.new_reg();
+                self.write(Instruction::Binary(op, result, value, r));
+                self.write(Instruction::StoreById(object, result, gid as _));
+                Ok(result)
+            }
+            ExprKind::ArrayIndex(array, index) => {
+                let array = self.compile(array, false)?;
+                let index = self.compile(index, false)?;
+                let value = self.new_reg();
+                self.write(Instruction::LoadByValue(value, array, index));
+                let r = self.compile(rhs, false)?;
+                let result = self.new_reg();
+                self.write(Instruction::Binary(op, result, value, r));
+                self.write(Instruction::StoreByValue(array, index, result));
+                Ok(result)
+            }
+            _ => Err(MsgWithPos::new(lhs.pos, Msg::LvalueExpected)),
         }
     }
 
+    /// Assigns the parts of `r` to the targets of `[a, b] = ...` or
+    /// `{x, y: this.y} = ...`.
+    pub fn compile_destructure(&mut self, target: &Expr, r: u16) -> Result<(), MsgWithPos> {
+        match &target.expr {
+            ExprKind::Array(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    let index = self.new_reg();
+                    self.write(Instruction::LoadInt(index, i as i32));
+                    let value = self.new_reg();
+                    self.write(Instruction::LoadByValue(value, r, index));
+                    self.compile_destructure(item, value)?;
+                }
+            }
+            ExprKind::NewObject(fields) => {
+                for field in fields.iter() {
+                    let (name, item) = match field {
+                        ObjectField::Field(name, item) => (name, item),
+                        ObjectField::Spread(item) => {
+                            return Err(MsgWithPos::new(item.pos, Msg::LvalueExpected))
+                        }
+                    };
+                    let value = self.new_reg();
+                    let (gid, _) = self.global(&Global::Str(name.to_owned()));
+                    self.write(Instruction::LoadById(value, r, gid as _));
+                    match item {
+                        Some(item) => self.compile_destructure(item, value)?,
+                        None => {
+                            let item = Expr {
+                                pos: target.pos,
+                                expr: ExprKind::Ident(name.to_owned()),
+                            };
+                            self.compile_destructure(&item, value)?
+                        }
+                    }
+                }
+            }
+            ExprKind::Ident(name) if self.is_immutable(name) => {
+                return Err(MsgWithPos::new(target.pos, Msg::LetReassigned));
+            }
+            _ => {
+                let acc = self.compile_access(target.pos, &target.expr)?;
+                self.access_set(target.pos, acc, r)?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn compile_binop(
         &mut self,
+        pos: Position,
         op: &str,
         e1: &Expr,
         e2: &Expr,
@@ -368,34 +537,6 @@ impl Context {
                 self.write(Instruction::Binary(BinOp::LessOrEqual, r3, r1, r2));
                 Ok(r3)
             }
-            "+" => {
-                let r1 = self.compile(e1, tail)?;
-                let r2 = self.compile(e2, tail)?;
-                let r3 = self.new_reg();
-                self.write(Instruction::Binary(BinOp::Add, r3, r1, r2));
-                Ok(r3)
-            }
-            "-" => {
-                let r1 = self.compile(e1, tail)?;
-                let r2 = self.compile(e2, tail)?;
-                let r3 = self.new_reg();
-                self.write(Instruction::Binary(BinOp::Sub, r3, r1, r2));
-                Ok(r3)
-            }
-            "/" => {
-                let r1 = self.compile(e1, tail)?;
-                let r2 = self.compile(e2, tail)?;
-                let r3 = self.new_reg();
-                self.write(Instruction::Binary(BinOp::Div, r3, r1, r2));
-                Ok(r3)
-            }
-            "*" => {
-                let r1 = self.compile(e1, tail)?;
-                let r2 = self.compile(e2, tail)?;
-                let r3 = self.new_reg();
-                self.write(Instruction::Binary(BinOp::Mul, r3, r1, r2));
-                Ok(r3)
-            }
             "&&" => {
                 let p = e1.pos;
                 self.compile(
@@ -444,28 +585,46 @@ impl Context {
                     false,
                 )
             }
-            "%" => {
-                let r1 = self.compile(e1, true)?;
-                let r2 = self.compile(e2, true)?;
-                let r3 = self.new_reg();
-                self.write(Instruction::Binary(BinOp::Mod, r3, r1, r2));
-                Ok(r3)
-            }
-            "<<" => {
-                let r1 = self.compile(e1, true)?;
-                let r2 = self.compile(e2, true)?;
-                let r3 = self.new_reg();
-                self.write(Instruction::Binary(BinOp::Rsh, r3, r1, r2));
-                Ok(r3)
+            "??" => {
+                let ret = self.new_reg();
+                let r = self.compile(e1, false)?;
+                self.write(Instruction::Move(ret, r));
+                let nil = self.new_reg();
+                let is_nil = self.new_reg();
+                self.write(Instruction::LoadNull(nil));
+                self.write(Instruction::Binary(BinOp::Equal, is_nil, ret, nil));
+                let check_bb = self.current_bb;
+                self.move_forward();
+                let r = self.compile(e2, false)?;
+                self.write(Instruction::Move(ret, r));
+                self.write(Instruction::Branch(self.current_bb as u16 + 1));
+                self.move_forward();
+                let end_bb = self.current_bb;
+                self.bbs[check_bb]
+                    .instructions
+                    .push(Instruction::ConditionalBranch(
+                        is_nil,
+                        check_bb as u16 + 1,
+                        end_bb as _,
+                    ));
+                Ok(ret)
             }
-            ">>" => {
-                let r1 = self.compile(e1, true)?;
-                let r2 = self.compile(e2, true)?;
+            _ => {
+                let op = match binop(op) {
+                    Some(op) => op,
+                    None => {
+                        return Err(MsgWithPos::new(
+                            pos,
+                            Msg::Custom(format!("unknown binary operator `{}`", op)),
+                        ))
+                    }
+                };
+                let r1 = self.compile(e1, false)?;
+                let r2 = self.compile(e2, false)?;
                 let r3 = self.new_reg();
-                self.write(Instruction::Binary(BinOp::Lsh, r3, r1, r2));
+                self.write(Instruction::Binary(op, r3, r1, r2));
                 Ok(r3)
             }
-            _ => unimplemented!(),
         }
     }
     pub fn ld_static(&mut self, name: &str) -> u16 {
@@ -475,6 +634,462 @@ impl Context {
         r
     }
 
+    /// Loads the atom `:name` from the static the module binds it to.
+    fn load_atom(&mut self, name: &str) -> u16 {
+        self.g.borrow_mut().atoms.insert(name.to_owned());
+        self.ld_static(&format!("atom:{}", name))
+    }
+
+    /// Makes the module start by binding each atom it uses to the static
+    /// `atom:name`. The first module using an atom creates it, so atoms are
+    /// shared by all modules and never equal to a string.
+    fn intern_atoms(&mut self) {
+        let atoms = self.g.borrow().atoms.iter().cloned().collect::<Vec<_>>();
+        let body = std::mem::take(&mut self.bbs[0].instructions);
+        let current_bb = std::mem::replace(&mut self.current_bb, 0);
+        for name in atoms.iter() {
+            let (gid, _) = self.global(&Global::Str(format!("atom:{}", name)));
+            let atom = self.new_reg();
+            self.write(Instruction::LoadStaticById(atom, gid as _));
+            let (sid, _) = self.global(&Global::Str(format!(":{}", name)));
+            let s = self.new_reg();
+            self.write(Instruction::LoadConst(s, sid as _));
+            let atom = self.call_intrinsic("__atom", &[atom, s]);
+            self.write(Instruction::StoreStaticById(atom, gid as _));
+        }
+        self.current_bb = current_bb;
+        self.bbs[0].instructions.extend(body);
+    }
+
+    /// Emits `instanceof(value, Array)`.
+    fn is_array(&mut self, value: u16) -> u16 {
+        let array = self.ld_static("Array");
+        self.write(Instruction::Push(value));
+        self.write(Instruction::Push(array));
+        let f = self.ld_static("instanceof");
+        let r = self.new_reg();
+        self.write(Instruction::Call(r, f, 2));
+        r
+    }
+
+    /// Emits `arr.length()`.
+    fn array_length(&mut self, arr: u16) -> u16 {
+        let f = self.new_reg();
+        let (gid, _) = self.global(&Global::Str("length".to_owned()));
+        self.write(Instruction::LoadById(f, arr, gid as _));
+        let r = self.new_reg();
+        self.write(Instruction::VirtCall(r, f, arr, 0));
+        r
+    }
+
+    /// Emits `arr.push(value)`.
+    fn array_push(&mut self, arr: u16, value: u16) {
+        self.write(Instruction::Push(value));
+        let f = self.new_reg();
+        let (gid, _) = self.global(&Global::Str("push".to_owned()));
+        self.write(Instruction::LoadById(f, arr, gid as _));
+        let r = self.new_reg();
+        self.write(Instruction::VirtCall(r, f, arr, 1));
+    }
+
+    /// Emits `obj.name`.
+    fn load_field(&mut self, obj: u16, name: &str) -> u16 {
+        let r = self.new_reg();
+        let (gid, _) = self.global(&Global::Str(name.to_owned()));
+        self.write(Instruction::LoadById(r, obj, gid as _));
+        r
+    }
+
+    /// Emits `obj.name = value`.
+    fn store_field(&mut self, obj: u16, value: u16, name: &str) {
+        let (gid, _) = self.global(&Global::Str(name.to_owned()));
+        self.write(Instruction::StoreById(obj, value, gid as _));
+    }
+
+    fn new_object(&mut self) -> u16 {
+        let object = self.ld_static("Object");
+        let r = self.new_reg();
+        self.write(Instruction::New(r, object, 0));
+        r
+    }
+
+    fn new_array(&mut self) -> u16 {
+        let array = self.ld_static("Array");
+        let r = self.new_reg();
+        self.write(Instruction::New(r, array, 0));
+        r
+    }
+
+    /// Copies `arr[from..to]` into a fresh array.
+    fn array_slice(&mut self, arr: u16, from: u16, to: u16) -> u16 {
+        let res = self.new_array();
+        let i = self.new_reg();
+        self.write(Instruction::Move(i, from));
+        self.write(Instruction::Branch(self.current_bb as u16 + 1));
+        self.move_forward();
+        let check_bb = self.current_bb;
+        let cond = self.new_reg();
+        self.write(Instruction::Binary(BinOp::Less, cond, i, to));
+        self.move_forward();
+        let elem = self.new_reg();
+        self.write(Instruction::LoadByValue(elem, arr, i));
+        self.array_push(res, elem);
+        let one = self.new_reg();
+        self.write(Instruction::LoadInt(one, 1));
+        self.write(Instruction::Binary(BinOp::Add, i, i, one));
+        self.write(Instruction::Branch(check_bb as _));
+        self.move_forward();
+        let end_bb = self.current_bb;
+        self.bbs[check_bb]
+            .instructions
+            .push(Instruction::ConditionalBranch(
+                cond,
+                check_bb as u16 + 1,
+                end_bb as _,
+            ));
+        res
+    }
+
+    /// Loads the compiler helper `name` (see `intrinsics`), compiling it
+    /// into the module the first time it is used.
+    pub fn intrinsic(&mut self, name: &str) -> u16 {
+        let global = Global::Var(name.to_owned());
+        if self.global2(&global).1 {
+            let src = intrinsics::source(name).expect("unknown intrinsic");
+            let mut ast = vec![];
+            Parser::new(Reader::from_string(src), &mut ast)
+                .parse()
+                .unwrap_or_else(|e| panic!("intrinsic {}: {}", name, e));
+            // Helpers must not capture anything from the code using them.
+            let mut ctx = Context::new();
+            ctx.g = self.g.clone();
+            for e in ast.iter() {
+                ctx.compile(e, false)
+                    .unwrap_or_else(|e| panic!("intrinsic {}: {}", name, e));
+            }
+        }
+        let (gid, _) = self.global(&global);
+        let r = self.new_reg();
+        self.write(Instruction::LoadConst(r, gid as _));
+        r
+    }
+
+    /// Whether `name` is a variable of this function or of an enclosing one.
+    fn is_local(&self, name: &str) -> bool {
+        self.in_scope_of(name, |_| ()).is_some()
+    }
+
+    /// Whether `callee` is the compiler-known `par_map`, unless a variable
+    /// or a function declared with that name hides it.
+    fn is_par_map(&self, callee: &Expr) -> bool {
+        match &callee.expr {
+            ExprKind::Ident(name) if name == "par_map" => {
+                !self.is_local(name) && !self.g.borrow().signatures.contains_key(name)
+            }
+            _ => false,
+        }
+    }
+
+    /// `par_map(array, f, workers)` maps `f` over `array` in `workers`
+    /// processes, see `__par_map`.
+    fn compile_par_map(&mut self, p: Position, args: &[Box<Expr>]) -> Result<u16, MsgWithPos> {
+        if args.len() != 3 || args.iter().any(|a| is_spread(a)) {
+            return Err(MsgWithPos::new(
+                p,
+                Msg::Custom(
+                    "`par_map` takes an array, a function and a number of workers".to_owned(),
+                ),
+            ));
+        }
+        let mut regs = vec![];
+        for arg in args.iter() {
+            regs.push(self.compile(arg, false)?);
+        }
+        Ok(self.call_intrinsic("__par_map", &regs))
+    }
+
+    /// Compiles `[value for pat in iter if cond]` as nested `for` loops
+    /// pushing `value` to a fresh array.
+    fn compile_comprehension(
+        &mut self,
+        value: &Expr,
+        clauses: &[ForClause],
+    ) -> Result<u16, MsgWithPos> {
+        let pos = value.pos;
+        // The array is only reachable from the generated `push`.
+        let name = "comprehension$array".to_owned();
+        let array = self.new_array();
+        self.locals.insert(name.clone(), array as _);
+        let push = Box::new(Expr {
+            pos,
+            expr: ExprKind::Access(
+                Box::new(Expr {
+                    pos,
+                    expr: ExprKind::Ident(name),
+                }),
+                "push".to_owned(),
+            ),
+        });
+        let mut body = Box::new(Expr {
+            pos,
+            expr: ExprKind::Call(push, vec![Box::new(value.clone())]),
+        });
+        for (pat, iter, conds) in clauses.iter().rev() {
+            for cond in conds.iter().rev() {
+                body = Box::new(Expr {
+                    pos: cond.pos,
+                    expr: ExprKind::If(cond.clone(), body, None),
+                });
+            }
+            body = Box::new(Expr {
+                pos: iter.pos,
+                expr: ExprKind::For(pat.clone(), iter.clone(), body),
+            });
+        }
+        self.compile(&body, false)?;
+        Ok(array)
+    }
+
+    /// Compiles `parallel`: every expression becomes the body of an `async`
+    /// task, then the tasks are awaited in order, so the first failure is
+    /// rethrown once the tasks before it finished.
+    fn compile_parallel(
+        &mut self,
+        bindings: &[(Box<Pattern>, Box<Expr>)],
+    ) -> Result<u16, MsgWithPos> {
+        let mut tasks = vec![];
+        for (_, value) in bindings.iter() {
+            let body = self.compile_function(&[], value, None)?;
+            tasks.push(self.call_intrinsic("__async", &[body]));
+        }
+        for ((pat, _), task) in bindings.iter().zip(tasks) {
+            let r = self.call_intrinsic("__await", &[task]);
+            self.compile_var_pattern(pat.pos, pat, false, r)?;
+        }
+        Ok(0)
+    }
+
+    /// Records the parameters of the functions `ast` declares at its top
+    /// level, before any call to them is compiled. Functions stored
+    /// elsewhere, like methods, are not called by their own name and have no
+    /// signature.
+    pub fn declare_signatures(&mut self, ast: &[Box<Expr>]) {
+        let mut g = self.g.borrow_mut();
+        for e in ast.iter() {
+            let (name, params) = match &e.expr {
+                ExprKind::Function(Some(name), params, _)
+                | ExprKind::Generator(Some(name), params, _)
+                | ExprKind::AsyncFunction(Some(name), params, _) => (name, params),
+                ExprKind::Let(_, pat, value) => match (&pat.decl, &value.expr) {
+                    (PatternDecl::Ident(name), ExprKind::Function(None, params, _)) => {
+                        (name, params)
+                    }
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            g.signatures.insert(name.clone(), params.clone());
+        }
+    }
+
+    /// Checks `f(x: 1)` against the record parameter of `f` when `f` names a
+    /// function declared with `function f(...)`.
+    fn check_keyword_args(&self, callee: &Expr, args: &[Box<Expr>]) -> Result<(), MsgWithPos> {
+        let (pos, fields) = match args.last() {
+            Some(arg) => match &arg.expr {
+                ExprKind::KeywordArgs(fields) => (arg.pos, fields),
+                _ => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+        let name = match &callee.expr {
+            ExprKind::Ident(name) => name,
+            _ => return Ok(()),
+        };
+        if self.is_local(name) {
+            return Ok(());
+        }
+        let params = match self.g.borrow().signatures.get(name) {
+            Some(params) => params.clone(),
+            None => return Ok(()),
+        };
+        let record = match params.get(args.len() - 1) {
+            Some(Arg::Record(record)) => record,
+            Some(Arg::Rest(_)) => return Ok(()),
+            _ => return Err(MsgWithPos::new(pos, Msg::NoKeywordArgs(name.clone()))),
+        };
+        for (field, value) in fields.iter() {
+            if !record.iter().any(|(n, _)| n == field) {
+                return Err(MsgWithPos::new(
+                    value.pos,
+                    Msg::UnknownKeywordArg(name.clone(), field.clone()),
+                ));
+            }
+        }
+        for (field, _) in record.iter() {
+            if !fields.iter().any(|(n, _)| n == field) {
+                return Err(MsgWithPos::new(
+                    pos,
+                    Msg::MissingKeywordArg(name.clone(), field.clone()),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds an array of `items`, appending all elements of the arrays
+    /// given as `...value`.
+    fn compile_spread_list(&mut self, items: &[Box<Expr>]) -> Result<u16, MsgWithPos> {
+        let mut array = self.new_array();
+        for item in items.iter() {
+            match &item.expr {
+                ExprKind::Spread(value) => {
+                    let value = self.compile(value, false)?;
+                    let concat = self.new_reg();
+                    let (gid, _) = self.global(&Global::Str("concat".to_owned()));
+                    self.write(Instruction::LoadById(concat, array, gid as _));
+                    self.write(Instruction::Push(value));
+                    let r = self.new_reg();
+                    self.write(Instruction::VirtCall(r, concat, array, 1));
+                    array = r;
+                }
+                _ => {
+                    let r = self.compile(item, false)?;
+                    self.array_push(array, r);
+                }
+            }
+        }
+        Ok(array)
+    }
+
+    /// Evaluates call arguments into registers. They are pushed by
+    /// `push_args` right before the call, a `yield` among them would lose
+    /// the ones already on the stack. Variables are copied since a later
+    /// argument may assign them.
+    fn compile_args(&mut self, args: &[Box<Expr>], tail: bool) -> Result<Vec<u16>, MsgWithPos> {
+        let mut regs = vec![];
+        for arg in args.iter() {
+            let mut r = self.compile(arg, tail)?;
+            if self.locals.values().any(|l| *l == r as i32) {
+                let copy = self.new_reg();
+                self.write(Instruction::Move(copy, r));
+                r = copy;
+            }
+            regs.push(r);
+        }
+        Ok(regs)
+    }
+
+    fn push_args(&mut self, args: &[u16]) {
+        for arg in args.iter() {
+            self.write(Instruction::Push(*arg));
+        }
+    }
+
+    pub fn call_intrinsic(&mut self, name: &str, args: &[u16]) -> u16 {
+        self.push_args(args);
+        let f = self.intrinsic(name);
+        let r = self.new_reg();
+        self.write(Instruction::Call(r, f, args.len() as _));
+        r
+    }
+
+    fn compile_for(&mut self, pat: &Pattern, iter: &Expr, body: &Expr) -> Result<u16, MsgWithPos> {
+        // Ranges are counted in registers, everything else goes through
+        // the `next()` protocol of `__iter`.
+        let (counter, it) = match &iter.expr {
+            ExprKind::Range(start, end, inclusive) => {
+                let start = self.compile(start, false)?;
+                let i = self.new_reg();
+                self.write(Instruction::Move(i, start));
+                let end = self.compile(end, false)?;
+                (Some((i, end, *inclusive)), 0)
+            }
+            _ => {
+                let value = self.compile(iter, false)?;
+                (None, self.call_intrinsic("__iter", &[value]))
+            }
+        };
+        self.write(Instruction::Branch(self.current_bb as u16 + 1));
+        self.move_forward();
+        let check_bb = self.current_bb;
+        let (cond, step) = match counter {
+            Some((i, end, inclusive)) => {
+                let cond = self.new_reg();
+                let op = if inclusive {
+                    BinOp::LessOrEqual
+                } else {
+                    BinOp::Less
+                };
+                self.write(Instruction::Binary(op, cond, i, end));
+                (cond, 0)
+            }
+            None => {
+                let next = self.new_reg();
+                let (gid, _) = self.global(&Global::Str("next".to_owned()));
+                self.write(Instruction::LoadById(next, it, gid as _));
+                let step = self.new_reg();
+                self.write(Instruction::VirtCall(step, next, it, 0));
+                let done = self.new_reg();
+                let (gid, _) = self.global(&Global::Str("done".to_owned()));
+                self.write(Instruction::LoadById(done, step, gid as _));
+                let cond = self.new_reg();
+                self.write(Instruction::Unary(UnaryOp::Not, cond, done));
+                (cond, step)
+            }
+        };
+        self.move_forward();
+        let break_bb = self.current_bb;
+        self.move_forward();
+        let continue_bb = self.current_bb;
+        self.move_forward();
+        let body_bb = self.current_bb;
+        self.scoped::<Result<(), MsgWithPos>, _>(|ctx| {
+            let value = match counter {
+                Some((i, _, _)) => i,
+                None => {
+                    let value = ctx.new_reg();
+                    let (gid, _) = ctx.global(&Global::Str("value".to_owned()));
+                    ctx.write(Instruction::LoadById(value, step, gid as _));
+                    value
+                }
+            };
+            ctx.compile_var_pattern(pat.pos, pat, false, value)?;
+            ctx.with_lci(
+                LoopControlInfo {
+                    break_point: break_bb as _,
+                    continue_point: continue_bb as _,
+                },
+                |ctx| ctx.compile(body, false),
+            )?;
+            Ok(())
+        })?;
+        self.write(Instruction::GcSafepoint);
+        self.write(Instruction::Branch(continue_bb as _));
+        self.move_forward();
+        let end_bb = self.current_bb;
+        self.bbs[check_bb]
+            .instructions
+            .push(Instruction::ConditionalBranch(
+                cond,
+                body_bb as _,
+                end_bb as _,
+            ));
+        self.bbs[break_bb]
+            .instructions
+            .push(Instruction::Branch(end_bb as _));
+        if let Some((i, _, _)) = counter {
+            let one = self.new_reg();
+            let bb = &mut self.bbs[continue_bb].instructions;
+            bb.push(Instruction::LoadInt(one, 1));
+            bb.push(Instruction::Binary(BinOp::Add, i, i, one));
+        }
+        self.bbs[continue_bb]
+            .instructions
+            .push(Instruction::Branch(check_bb as _));
+        Ok(0)
+    }
+
     pub fn compile(&mut self, e: &Expr, tail: bool) -> Result<u16, MsgWithPos> {
         match &e.expr {
             ExprKind::Throw(e) => {
@@ -482,6 +1097,29 @@ impl Context {
                 self.write(Instruction::Throw(r));
                 Ok(0)
             }
+            ExprKind::Try(body, catches, finally) => self.compile_try(body, catches, finally),
+            ExprKind::Defer(_) => Err(MsgWithPos::new(
+                e.pos,
+                Msg::Custom("`defer` is only allowed directly inside a block".to_owned()),
+            )),
+            ExprKind::Break | ExprKind::Continue => {
+                let depth = self.loop_control_info.len();
+                if depth == 0 {
+                    return Err(MsgWithPos::new(e.pos, Msg::OutsideLoop));
+                }
+                let keep = self
+                    .try_stack
+                    .iter()
+                    .take_while(|t| t.loop_depth < depth)
+                    .count();
+                self.unwind_tries(keep)?;
+                if let ExprKind::Break = e.expr {
+                    self.write_break();
+                } else {
+                    self.write_continue();
+                }
+                Ok(0)
+            }
             ExprKind::Block(v) => {
                 if v.is_empty() {
                     let r = self.new_reg();
@@ -496,7 +1134,17 @@ impl Context {
                     ctx.write(Instruction::Branch(expr_next_bb as _));
                     ctx.move_forward();
                     let mut last = None;
-                    for x in v.iter() {
+                    for (i, x) in v.iter().enumerate() {
+                        if let ExprKind::Defer(deferred) = &x.expr {
+                            // The rest of the block runs as `try { rest } finally
+                            // { deferred }`, so later defers run first.
+                            let rest = Expr {
+                                pos: x.pos,
+                                expr: ExprKind::Block(v[i + 1..].to_vec()),
+                            };
+                            last = Some(ctx.compile_try(&rest, &[], &Some(deferred.clone()))?);
+                            break;
+                        }
                         let r = ctx.compile(x, tail)?;
                         last = Some(r);
                     }
@@ -514,7 +1162,7 @@ impl Context {
                     }
                 }
             }
-            ExprKind::BinOp(e1, op, e2) => self.compile_binop(op, e1, e2, tail),
+            ExprKind::BinOp(e1, op, e2) => self.compile_binop(e.pos, op, e1, e2, tail),
             ExprKind::ConstInt(x) => {
                 let r = self.new_reg();
                 if *x >= std::i32::MAX as i64 {
@@ -535,15 +1183,35 @@ impl Context {
                 self.write(Instruction::LoadConst(g, gid as _));
                 Ok(g)
             }
+            ExprKind::ConstChar(c) => {
+                let (gid, _) = self.global(&Global::Str(c.to_string()));
+                let g = self.new_reg();
+                self.write(Instruction::LoadConst(g, gid as _));
+                Ok(g)
+            }
+            ExprKind::ConstAtom(s) => Ok(self.load_atom(s)),
             ExprKind::Return(e) => match e {
                 Some(e) => {
-                    let r = self.compile(e, true)?;
+                    let tail = self.try_stack.is_empty() && self.generator.is_none();
+                    let r = self.compile(e, tail)?;
+                    self.unwind_tries(0)?;
+                    let r = if self.generator.is_some() {
+                        self.generator_return(r)
+                    } else {
+                        r
+                    };
                     self.write(Instruction::Return(Some(r)));
                     self.move_forward();
                     Ok(r)
                 }
                 _ => {
-                    self.write(Instruction::Return(None));
+                    self.unwind_tries(0)?;
+                    if self.generator.is_some() {
+                        let r = self.generator_return(0);
+                        self.write(Instruction::Return(Some(r)));
+                    } else {
+                        self.write(Instruction::Return(None));
+                    }
                     self.move_forward();
                     Ok(0)
                 }
@@ -618,10 +1286,20 @@ impl Context {
 
                 Ok(r)
             }
-            ExprKind::Assign(lhs, rhs) => {
-                let a = self.compile_access(&lhs.expr);
-                let r = self.compile(rhs, false)?;
-                self.access_set(lhs.pos, a, r)
+            ExprKind::Assign(lhs, rhs) => match &lhs.expr {
+                ExprKind::Array(_) | ExprKind::NewObject(_) => {
+                    let r = self.compile(rhs, false)?;
+                    self.compile_destructure(lhs, r)?;
+                    Ok(r)
+                }
+                _ => {
+                    let a = self.compile_access(lhs.pos, &lhs.expr)?;
+                    let r = self.compile(rhs, false)?;
+                    self.access_set(lhs.pos, a, r)
+                }
+            },
+            ExprKind::CompoundAssign(op, lhs, rhs) => {
+                self.compile_compound_assign(e.pos, op, lhs, rhs)
             }
             ExprKind::If(cond, if_true, if_false) => {
                 let before_bb_id = self.current_bb;
@@ -667,6 +1345,44 @@ impl Context {
                     .push(Instruction::Branch(end_bb_id as _));
                 Ok(ret)
             }
+            ExprKind::IfLet(pat, value, if_true, if_false) => {
+                let ret = self.new_reg();
+                let value = self.compile(value, false)?;
+                let mut to_terminate = vec![];
+                let (ok, check_bb) = self.scoped::<Result<(u16, usize), MsgWithPos>, _>(|fb| {
+                    let ok = fb.compile_let_condition(pat, value)?;
+                    let check_bb = fb.current_bb;
+                    fb.move_forward();
+                    let last = fb.compile(if_true, tail)?;
+                    fb.write(Instruction::Move(ret, last));
+                    to_terminate.push(fb.current_bb);
+                    fb.move_forward();
+                    Ok((ok, check_bb))
+                })?;
+                let else_bb = self.current_bb;
+                if let Some(if_false) = if_false {
+                    let last = self.compile(if_false, tail)?;
+                    self.write(Instruction::Move(ret, last));
+                } else {
+                    self.write(Instruction::LoadNull(ret));
+                }
+                to_terminate.push(self.current_bb);
+                self.move_forward();
+                let end_bb = self.current_bb;
+                for bb in to_terminate {
+                    self.bbs[bb]
+                        .instructions
+                        .push(Instruction::Branch(end_bb as _));
+                }
+                self.bbs[check_bb]
+                    .instructions
+                    .push(Instruction::ConditionalBranch(
+                        ok,
+                        check_bb as u16 + 1,
+                        else_bb as _,
+                    ));
+                Ok(ret)
+            }
             ExprKind::Ident(s) => {
                 /*let s: &str = s;
                 if self.locals.contains_key(s) {
@@ -708,13 +1424,55 @@ impl Context {
                 let r = self.compile_function(params, body, name.clone())?;
                 return Ok(r);
             }
+            ExprKind::Generator(name, params, body) => {
+                self.compile_generator(params, body, name.clone())
+            }
+            ExprKind::AsyncFunction(name, params, body) => {
+                self.compile_function_with(params, body.pos, name.clone(), |ctx| {
+                    let body = ctx.compile_function(&[], body, None)?;
+                    Ok(ctx.call_intrinsic("__async", &[body]))
+                })
+            }
+            ExprKind::Comprehension(value, clauses) => {
+                self.scoped(|ctx| ctx.compile_comprehension(value, clauses))
+            }
+            ExprKind::Parallel(bindings) => self.compile_parallel(bindings),
+            ExprKind::Expansion(name, body) => self.compile(body, tail).map_err(|err| {
+                MsgWithPos::new(
+                    e.pos,
+                    Msg::InMacro(name.clone(), err.pos, Box::new(err.msg)),
+                )
+            }),
+            ExprKind::Await(task) => {
+                let task = self.compile(task, false)?;
+                Ok(self.call_intrinsic("__await", &[task]))
+            }
+            ExprKind::Yield(value) => self.compile_yield(e.pos, value),
+            ExprKind::YieldFrom(iterable) => {
+                // `for value in iterable { yield value }`
+                let name = "yield*".to_owned();
+                let pat = Box::new(Pattern {
+                    decl: PatternDecl::Ident(name.clone()),
+                    pos: e.pos,
+                });
+                let value = Box::new(Expr {
+                    pos: e.pos,
+                    expr: ExprKind::Ident(name),
+                });
+                let body = Expr {
+                    pos: e.pos,
+                    expr: ExprKind::Yield(value),
+                };
+                self.scoped(|ctx| ctx.compile_for(&pat, iterable, &body))?;
+                let r = self.new_reg();
+                self.write(Instruction::LoadNull(r));
+                Ok(r)
+            }
             ExprKind::New(expr) => match &expr.expr {
                 ExprKind::Call(value, args) => {
-                    for arg in args.iter() {
-                        let r = self.compile(arg, tail)?;
-                        self.write(Instruction::Push(r));
-                    }
+                    let args = self.compile_args(args, tail)?;
                     let value = self.compile(value, tail)?;
+                    self.push_args(&args);
                     let r = self.new_reg();
                     self.write(Instruction::New(r, value, args.len() as _));
                     Ok(r)
@@ -722,6 +1480,38 @@ impl Context {
                 _ => panic!("Call expected"),
             },
 
+            ExprKind::WhileLet(pat, value, block) => {
+                self.scoped::<Result<u16, MsgWithPos>, _>(|fb| {
+                    let check_bb = fb.current_bb as u16 + 1;
+                    fb.write(Instruction::Branch(check_bb));
+                    fb.move_forward();
+                    let value = fb.compile(value, false)?;
+                    let ok = fb.compile_let_condition(pat, value)?;
+                    let branch_bb = fb.current_bb;
+                    let break_bb = fb.current_bb as u16 + 1;
+                    fb.move_forward();
+                    let body_bb = fb.current_bb as u16 + 1;
+                    fb.move_forward();
+                    let last = fb.with_lci(
+                        LoopControlInfo {
+                            break_point: break_bb,
+                            continue_point: check_bb,
+                        },
+                        |fb| fb.compile(block, tail),
+                    )?;
+                    fb.write(Instruction::GcSafepoint);
+                    fb.write(Instruction::Branch(check_bb));
+                    fb.move_forward();
+                    let end_bb = fb.current_bb as u16;
+                    fb.bbs[branch_bb]
+                        .instructions
+                        .push(Instruction::ConditionalBranch(ok, body_bb, break_bb));
+                    fb.bbs[break_bb as usize]
+                        .instructions
+                        .push(Instruction::Branch(end_bb));
+                    Ok(last)
+                })
+            }
             ExprKind::While(cond, block) => {
                 let r = self.scoped::<Result<u16, MsgWithPos>, _>(|fb| {
                     let expr_check_bb_id = fb.current_bb as u16 + 1;
@@ -765,6 +1555,19 @@ impl Context {
                 })?;
                 Ok(r)
             }
+            ExprKind::For(pat, iter, body) => self.scoped(|ctx| ctx.compile_for(pat, iter, body)),
+            ExprKind::Range(start, end, inclusive) => {
+                let start = self.compile(start, false)?;
+                let end = self.compile(end, false)?;
+                let inclusive_r = self.new_reg();
+                if *inclusive {
+                    self.write(Instruction::LoadTrue(inclusive_r));
+                } else {
+                    self.write(Instruction::LoadFalse(inclusive_r));
+                }
+                Ok(self.call_intrinsic("__range", &[start, end, inclusive_r]))
+            }
+            ExprKind::Array(items) => self.compile_spread_list(items),
             ExprKind::ArrayIndex(value, index) => {
                 let value = self.compile(value, tail)?;
                 let index = self.compile(index, tail)?;
@@ -772,26 +1575,126 @@ impl Context {
                 self.write(Instruction::LoadByValue(r, value, index));
                 Ok(r)
             }
+            ExprKind::Call(value, args) if self.is_par_map(value) => {
+                self.compile_par_map(e.pos, args)
+            }
+            ExprKind::Call(value, args) if args.iter().any(|a| is_spread(a)) => {
+                let (f, this) = match &value.expr {
+                    ExprKind::Access(object, field) => {
+                        let this = self.compile(object, false)?;
+                        let f = self.new_reg();
+                        let (gid, _) = self.global(&Global::Str(field.to_owned()));
+                        self.write(Instruction::LoadById(f, this, gid as _));
+                        (f, this)
+                    }
+                    _ => {
+                        let f = self.compile(value, false)?;
+                        let this = self.new_reg();
+                        self.write(Instruction::LoadNull(this));
+                        (f, this)
+                    }
+                };
+                let args = self.compile_spread_list(args)?;
+                let apply = self.new_reg();
+                let (gid, _) = self.global(&Global::Str("apply".to_owned()));
+                self.write(Instruction::LoadById(apply, f, gid as _));
+                self.write(Instruction::Push(this));
+                self.write(Instruction::Push(args));
+                let r = self.new_reg();
+                self.write(Instruction::VirtCall(r, apply, f, 2));
+                Ok(r)
+            }
+            ExprKind::Spread(_) => Err(MsgWithPos::new(
+                e.pos,
+                Msg::Custom(
+                    "`...` is only allowed in calls, array literals and object literals".to_owned(),
+                ),
+            )),
+            ExprKind::KeywordArgs(fields) => {
+                let fields = fields
+                    .iter()
+                    .map(|(name, value)| ObjectField::Field(name.clone(), Some(value.clone())))
+                    .collect();
+                self.compile(
+                    &Expr {
+                        pos: e.pos,
+                        expr: ExprKind::NewObject(fields),
+                    },
+                    false,
+                )
+            }
             ExprKind::Call(value, args) => {
-                for arg in args.iter() {
-                    let r = self.compile(arg, tail)?;
-                    self.write(Instruction::Push(r));
+                self.check_keyword_args(value, args)?;
+                // In `a?.f(x)` the arguments must not be pushed before `a` is
+                // known not to be nil.
+                let callee_first = in_safe_chain(value);
+                let mut arg_regs = vec![];
+                if !callee_first {
+                    arg_regs = self.compile_args(args, tail)?;
                 }
-                match &value.expr {
+                let (value, this) = match &value.expr {
                     ExprKind::Access(object, fields) => {
                         let this = self.compile(object, tail)?;
                         let field = self.new_reg();
                         let (s, _) = self.global(&Global::Str(fields.to_owned()));
                         self.write(Instruction::LoadById(field, this, s as _));
-                        let r = self.new_reg();
-                        self.write(Instruction::VirtCall(r, field, this, args.len() as _));
-                        return Ok(r);
+                        (field, Some(this))
                     }
-                    _ => (),
+                    _ => (self.compile(value, tail)?, None),
+                };
+                if callee_first {
+                    arg_regs = self.compile_args(args, tail)?;
                 }
-                let value = self.compile(value, tail)?;
+                self.push_args(&arg_regs);
                 let r = self.new_reg();
-                self.write(Instruction::Call(r, value, args.len() as _));
+                match this {
+                    Some(this) => {
+                        self.write(Instruction::VirtCall(r, value, this, args.len() as _))
+                    }
+                    None => self.write(Instruction::Call(r, value, args.len() as _)),
+                }
+                Ok(r)
+            }
+            ExprKind::Optional(chain) => {
+                let ret = self.new_reg();
+                self.optional_exits.push(vec![]);
+                let r = self.compile(chain, false)?;
+                let exits = self.optional_exits.pop().unwrap();
+                self.write(Instruction::Move(ret, r));
+                let done_bb = self.current_bb;
+                self.move_forward();
+                let nil_bb = self.current_bb;
+                self.write(Instruction::LoadNull(ret));
+                self.move_forward();
+                let end_bb = self.current_bb;
+                for bb in [done_bb, nil_bb].iter() {
+                    self.bbs[*bb]
+                        .instructions
+                        .push(Instruction::Branch(end_bb as _));
+                }
+                for (bb, next, is_nil) in exits {
+                    self.bbs[bb]
+                        .instructions
+                        .push(Instruction::ConditionalBranch(
+                            is_nil,
+                            nil_bb as _,
+                            next as _,
+                        ));
+                }
+                Ok(ret)
+            }
+            ExprKind::SafeNav(object) => {
+                let r = self.compile(object, false)?;
+                let nil = self.new_reg();
+                let is_nil = self.new_reg();
+                self.write(Instruction::LoadNull(nil));
+                self.write(Instruction::Binary(BinOp::Equal, is_nil, r, nil));
+                let bb = self.current_bb;
+                self.move_forward();
+                match self.optional_exits.last_mut() {
+                    Some(exits) => exits.push((bb, bb + 1, is_nil)),
+                    None => unreachable!("`?.` outside of an optional chain"),
+                }
                 Ok(r)
             }
             ExprKind::Unop(op, val) => {
@@ -801,18 +1704,23 @@ impl Context {
                 match op {
                     "-" => self.write(Instruction::Unary(UnaryOp::Neg, dest, r)),
                     "!" => self.write(Instruction::Unary(UnaryOp::Not, dest, r)),
+                    "~" => {
+                        let ones = self.new_reg();
+                        self.write(Instruction::LoadInt(ones, -1));
+                        self.write(Instruction::Binary(BinOp::Xor, dest, r, ones));
+                    }
                     _ => self.write(Instruction::Move(dest, r)),
                 }
                 Ok(dest)
             }
             ExprKind::Lambda(arguments, body) => self.compile_function(arguments, body, None),
             ExprKind::Access(f, s) => {
-                let acc = self.compile_access(&ExprKind::Access(f.clone(), s.clone()));
+                let acc = self.compile_access(e.pos, &ExprKind::Access(f.clone(), s.clone()))?;
                 self.access_get(acc)
             }
             ExprKind::This => {
                 let r = self.new_reg();
-                self.write(Instruction::LoadThis(r));
+                self.load_this(r);
                 Ok(r)
             }
             ExprKind::ConstBool(val) => {
@@ -832,7 +1740,15 @@ impl Context {
                 self.write(Instruction::New(r2, r, 0));
                 let r = r2;
 
-                for (name, value) in fields.iter() {
+                for field in fields.iter() {
+                    let (name, value) = match field {
+                        ObjectField::Field(name, value) => (name, value),
+                        ObjectField::Spread(source) => {
+                            let source = self.compile(source, false)?;
+                            self.call_intrinsic("__assign", &[r, source]);
+                            continue;
+                        }
+                    };
                     let value = if let Some(value) = value {
                         self.compile(value, tail)?
                     } else {
@@ -845,6 +1761,7 @@ impl Context {
                 Ok(r)
             }
             ExprKind::Match(e2, patterns) => self.compile_match(e.pos, e2, patterns),
+            ExprKind::Receive(arms, after) => self.compile_receive(arms, after),
             ExprKind::Nil => {
                 let r = self.new_reg();
                 self.write(Instruction::LoadNull(r));
@@ -857,13 +1774,40 @@ impl Context {
         &mut self,
         _: Position,
         e: &Box<Expr>,
-        patterns: &[(Box<Pattern>, Option<Box<Expr>>, Box<Expr>)],
+        patterns: &[MatchArm],
     ) -> Result<u16, MsgWithPos> {
         let value = self.compile(e, false)?;
+        let to_terminate = self.compile_arms(value, patterns, 0, |_| Ok(()))?;
+        let id = self.current_bb;
+        for bb in to_terminate.iter() {
+            self.bbs[*bb]
+                .instructions
+                .push(Instruction::Branch(id as _));
+        }
+        let r = self.new_reg();
+        self.write(Instruction::Move(r, 0));
+        Ok(0)
+    }
+
+    /// Compiles the arms of a `match` or `receive` on `value`. `enter` runs
+    /// before the body of the arm that matched and the value of the body is
+    /// moved to `result`. Returns the blocks that still need a branch past
+    /// the arms, code for when no arm matched goes in the current block.
+    fn compile_arms<F>(
+        &mut self,
+        value: u16,
+        patterns: &[MatchArm],
+        result: u16,
+        mut enter: F,
+    ) -> Result<Vec<usize>, MsgWithPos>
+    where
+        F: FnMut(&mut Context) -> Result<(), MsgWithPos>,
+    {
         let mut to_terminate = vec![];
 
         for (pattern, when, body) in patterns.iter() {
             let tmp = self.locals.clone();
+            let tmp_immutable = self.immutable.clone();
             let r = self.compile_pattern(pattern.pos, pattern, value)?;
             let first = self.current_bb;
             let first_r = r;
@@ -880,8 +1824,9 @@ impl Context {
                 self.move_forward();
                 second_b = Some(self.current_bb);
             }
+            enter(self)?;
             let r = self.compile(body, false)?;
-            self.write(Instruction::Move(0, r));
+            self.write(Instruction::Move(result, r));
             to_terminate.push(self.current_bb);
             self.move_forward();
             let terminator_id = self.current_bb;
@@ -902,17 +1847,244 @@ impl Context {
                     ));
             }
             self.locals = tmp;
+            self.immutable = tmp_immutable;
         }
-        //self.move_forward();
-        let id = self.current_bb;
-        for bb in to_terminate.iter() {
-            self.bbs[*bb]
+        Ok(to_terminate)
+    }
+
+    /// Compiles `receive`. Messages no arm matches are set aside and sent
+    /// back to the current process once an arm matched or the timer of
+    /// `after` fired. They keep their order among themselves but come after
+    /// the messages that arrived in the meantime. An arm matching cancels
+    /// the timer, and timeouts of cancelled timers are dropped.
+    fn compile_receive(
+        &mut self,
+        arms: &[MatchArm],
+        after: &Option<(Box<Expr>, Box<Expr>)>,
+    ) -> Result<u16, MsgWithPos> {
+        let result = self.new_reg();
+        self.write(Instruction::LoadNull(result));
+        let others = self.new_array();
+        let timer = match after {
+            Some((timeout, _)) => {
+                let timeout = self.compile(timeout, false)?;
+                self.call_intrinsic("__timer", &[timeout])
+            }
+            None => 0,
+        };
+        self.write(Instruction::Branch(self.current_bb as u16 + 1));
+        self.move_forward();
+        let loop_bb = self.current_bb;
+        let process = self.ld_static("Process");
+        let recv = self.load_field(process, "recv");
+        let message = self.new_reg();
+        self.write(Instruction::VirtCall(message, recv, process, 0));
+        let stale = self.call_intrinsic("__is_stale", &[message]);
+        self.write(Instruction::ConditionalBranch(
+            stale,
+            loop_bb as _,
+            self.current_bb as u16 + 1,
+        ));
+        self.move_forward();
+
+        let mut exits = vec![];
+        if let Some((_, body)) = after {
+            let timed_out = self.call_intrinsic("__is_timeout", &[message, timer]);
+            let check_bb = self.current_bb;
+            self.move_forward();
+            self.call_intrinsic("__requeue", &[others]);
+            let r = self.compile(body, false)?;
+            self.write(Instruction::Move(result, r));
+            exits.push(self.current_bb);
+            self.move_forward();
+            self.bbs[check_bb]
                 .instructions
-                .push(Instruction::Branch(id as _));
+                .push(Instruction::ConditionalBranch(
+                    timed_out,
+                    check_bb as u16 + 1,
+                    self.current_bb as _,
+                ));
+        }
+        exits.extend(self.compile_arms(message, arms, result, |ctx| {
+            if timer != 0 {
+                let cancelled = ctx.new_reg();
+                ctx.write(Instruction::LoadTrue(cancelled));
+                ctx.store_field(timer, cancelled, "cancelled");
+            }
+            ctx.call_intrinsic("__requeue", &[others]);
+            Ok(())
+        })?);
+        self.array_push(others, message);
+        self.write(Instruction::Branch(loop_bb as _));
+        self.move_forward();
+        for bb in exits {
+            self.bbs[bb]
+                .instructions
+                .push(Instruction::Branch(self.current_bb as _));
+        }
+        Ok(result)
+    }
+
+    pub fn compile_try(
+        &mut self,
+        body: &Expr,
+        catches: &[MatchArm],
+        finally: &Option<Box<Expr>>,
+    ) -> Result<u16, MsgWithPos> {
+        let ret = self.new_reg();
+        let exc = self.new_reg();
+        let info = TryInfo {
+            finally: finally.clone(),
+            loop_depth: self.loop_control_info.len(),
+            locals: self.locals.clone(),
+        };
+        let setup_bb = self.begin_try();
+        self.try_stack.push(info.clone());
+        let last = self.compile(body, false)?;
+        self.write(Instruction::Move(ret, last));
+        self.unwind_tries(self.try_stack.len() - 1)?;
+        self.try_stack.pop();
+        let mut to_terminate = vec![self.current_bb];
+        self.move_forward();
+        self.install_handler(setup_bb, exc, self.current_bb);
+
+        if catches.is_empty() {
+            if let Some(finally) = finally {
+                self.compile(finally, false)?;
+            }
+            self.write(Instruction::Throw(exc));
+            self.move_forward();
+        } else {
+            // A second handler covers the catch arms so that `finally` also
+            // runs when an arm throws or no arm matches.
+            let rethrow = self.new_reg();
+            let rethrow_setup_bb = match finally {
+                Some(_) => {
+                    self.try_stack.push(info.clone());
+                    Some(self.begin_try())
+                }
+                None => None,
+            };
+            for (pat, guard, block) in catches.iter() {
+                self.scoped::<Result<(), MsgWithPos>, _>(|fb| {
+                    let mut branches = vec![];
+                    let r = fb.compile_pattern(pat.pos, pat, exc)?;
+                    fb.pattern_check(r, &mut branches);
+                    if let Some(guard) = guard {
+                        let r = fb.compile(guard, false)?;
+                        fb.pattern_check(r, &mut branches);
+                    }
+                    let last = fb.compile(block, false)?;
+                    fb.write(Instruction::Move(ret, last));
+                    if rethrow_setup_bb.is_some() {
+                        fb.unwind_tries(fb.try_stack.len() - 1)?;
+                    }
+                    to_terminate.push(fb.current_bb);
+                    fb.move_forward();
+                    let next_arm = fb.current_bb;
+                    for (branch, next) in branches {
+                        fb.bbs[branch]
+                            .instructions
+                            .push(Instruction::ConditionalBranch(0, next as _, next_arm as _));
+                    }
+                    Ok(())
+                })?;
+            }
+            self.write(Instruction::Throw(exc));
+            self.move_forward();
+            if let (Some(setup_bb), Some(finally)) = (rethrow_setup_bb, finally) {
+                self.try_stack.pop();
+                self.install_handler(setup_bb, rethrow, self.current_bb);
+                self.compile(finally, false)?;
+                self.write(Instruction::Throw(rethrow));
+                self.move_forward();
+            }
+        }
+
+        let end_bb = self.current_bb;
+        for bb in to_terminate {
+            self.bbs[bb]
+                .instructions
+                .push(Instruction::Branch(end_bb as _));
+        }
+        Ok(ret)
+    }
+
+    /// Ends the current block with a jump to an empty block that gets the
+    /// `CatchBlock` from `install_handler` once the handler block is known.
+    fn begin_try(&mut self) -> usize {
+        let setup_bb = self.current_bb + 1;
+        self.write(Instruction::Branch(setup_bb as _));
+        self.move_forward();
+        self.move_forward();
+        setup_bb
+    }
+
+    fn install_handler(&mut self, setup_bb: usize, exc: u16, handler_bb: usize) {
+        let instructions = &mut self.bbs[setup_bb].instructions;
+        instructions.push(Instruction::CatchBlock(exc, handler_bb as _));
+        instructions.push(Instruction::Branch(setup_bb as u16 + 1));
+    }
+
+    /// Leaves the `try` blocks above `depth`, innermost first: drops their
+    /// handlers and inlines their `finally` blocks.
+    fn unwind_tries(&mut self, depth: usize) -> Result<(), MsgWithPos> {
+        let saved = self.try_stack.clone();
+        while self.try_stack.len() > depth {
+            let info = self.try_stack.pop().unwrap();
+            self.write(Instruction::PopCatch);
+            if let Some(finally) = &info.finally {
+                let locals = std::mem::replace(&mut self.locals, info.locals.clone());
+                self.compile(finally, false)?;
+                self.locals = locals;
+            }
+        }
+        self.try_stack = saved;
+        Ok(())
+    }
+
+    /// Tests `val` against the pattern of `if let`/`while let`. `nil` only
+    /// matches the `nil` and `_` patterns, so `while let x = it.next()` stops
+    /// at the end of the input.
+    fn compile_let_condition(&mut self, pat: &Pattern, val: u16) -> Result<u16, MsgWithPos> {
+        match pat.decl {
+            PatternDecl::Nil | PatternDecl::Pass => self.compile_pattern(pat.pos, pat, val),
+            _ => {
+                let mut branches = vec![];
+                let nil = self.new_reg();
+                let r = self.new_reg();
+                self.write(Instruction::LoadNull(nil));
+                self.write(Instruction::Binary(BinOp::NotEqual, r, val, nil));
+                self.pattern_check(r, &mut branches);
+                let r = self.compile_pattern(pat.pos, pat, val)?;
+                self.pattern_check(r, &mut branches);
+                Ok(self.pattern_join(branches))
+            }
+        }
+    }
+
+    /// Ends the current block of a pattern test: if `r` is false the pattern
+    /// fails and control goes to the block finished by `pattern_join`.
+    fn pattern_check(&mut self, r: u16, branches: &mut Vec<(usize, usize)>) {
+        self.write(Instruction::Move(0, r));
+        branches.push((self.current_bb, self.current_bb + 1));
+        self.move_forward();
+    }
+
+    fn pattern_join(&mut self, branches: Vec<(usize, usize)>) -> u16 {
+        let terminator_bb_id = self.current_bb;
+        for (branch, next) in branches {
+            self.bbs[branch]
+                .instructions
+                .push(Instruction::ConditionalBranch(
+                    0,
+                    next as _,
+                    terminator_bb_id as _,
+                ));
         }
         let r = self.new_reg();
         self.write(Instruction::Move(r, 0));
-        Ok(0)
+        r
     }
 
     fn compile_pattern(
@@ -922,37 +2094,41 @@ impl Context {
         val: u16,
     ) -> Result<u16, MsgWithPos> {
         match &pat.decl {
-            PatternDecl::ConstChar(c) => {
-                let r = self.new_reg();
-                let (gid, _) = self.global(&Global::Str(c.to_string()));
+            PatternDecl::ConstChar(_) | PatternDecl::ConstFloat(_) | PatternDecl::ConstInt(_) => {
+                let r = self.compile_pattern_const(pat)?;
                 let r2 = self.new_reg();
-                self.write(Instruction::LoadConst(r, gid as _));
                 self.write(Instruction::Binary(BinOp::Equal, r2, r, val));
                 Ok(r2)
             }
-            PatternDecl::ConstFloat(f) => {
+            PatternDecl::ConstStr(s) => {
                 let r = self.new_reg();
                 let r2 = self.new_reg();
-                self.write(Instruction::LoadNumber(r, f.to_bits()));
+                let (gid, _) = self.global(&Global::Str(s.to_owned()));
+                self.write(Instruction::LoadConst(r, gid as _));
                 self.write(Instruction::Binary(BinOp::Equal, r2, r, val));
                 Ok(r2)
             }
-            PatternDecl::ConstInt(f) => {
+            PatternDecl::ConstBool(b) => {
                 let r = self.new_reg();
                 let r2 = self.new_reg();
-                if *f < std::i32::MAX as i64 {
-                    self.write(Instruction::LoadInt(r, *f as i32));
+                if *b {
+                    self.write(Instruction::LoadTrue(r));
                 } else {
-                    self.write(Instruction::LoadNumber(r, (*f as f64).to_bits()))
+                    self.write(Instruction::LoadFalse(r));
                 }
                 self.write(Instruction::Binary(BinOp::Equal, r2, r, val));
                 Ok(r2)
             }
-            PatternDecl::ConstStr(s) => {
+            PatternDecl::Nil => {
                 let r = self.new_reg();
                 let r2 = self.new_reg();
-                let (gid, _) = self.global(&Global::Str(s.to_owned()));
-                self.write(Instruction::LoadConst(r, gid as _));
+                self.write(Instruction::LoadNull(r));
+                self.write(Instruction::Binary(BinOp::Equal, r2, r, val));
+                Ok(r2)
+            }
+            PatternDecl::ConstAtom(s) => {
+                let r = self.load_atom(s);
+                let r2 = self.new_reg();
                 self.write(Instruction::Binary(BinOp::Equal, r2, r, val));
                 Ok(r2)
             }
@@ -967,60 +2143,171 @@ impl Context {
             }
             PatternDecl::Record(fields) => {
                 let mut branches = vec![];
+                let t = self.new_reg();
+                self.write(Instruction::LoadTrue(t));
+                self.pattern_check(t, &mut branches);
                 for (name, pat) in fields.iter() {
                     let r = self.new_reg();
                     let (gid, _) = self.global(&Global::Str(name.to_owned()));
                     self.write(Instruction::LoadById(r, val, gid as _));
                     if let Some(pat) = pat {
                         let r = self.compile_pattern(pat.pos, pat, r)?;
-                        self.write(Instruction::Move(0, r));
-                        branches.push((self.current_bb, self.current_bb + 1));
+                        self.pattern_check(r, &mut branches);
                     } else {
-                        self.write(Instruction::LoadTrue(0));
+                        self.immutable.insert(name.to_owned());
+                        self.locals.insert(name.to_owned(), r as _);
+                    }
+                }
+                Ok(self.pattern_join(branches))
+            }
+            PatternDecl::Or(alts) => {
+                let names = pat.bindings();
+                let mut slots = vec![];
+                let mut tests = vec![];
+                let mut to_join = vec![];
+                for (i, alt) in alts.iter().enumerate() {
+                    let r = self.compile_pattern(alt.pos, alt, val)?;
+                    // Every alternative leaves its bindings in the registers
+                    // picked by the first one.
+                    if i == 0 {
+                        for name in names.iter() {
+                            slots.push(*self.locals.get(name).unwrap());
+                        }
+                    } else {
+                        for (name, slot) in names.iter().zip(slots.iter()) {
+                            let loc = *self.locals.get(name).unwrap();
+                            self.write(Instruction::Move(*slot as _, loc as _));
+                            self.locals.insert(name.to_owned(), *slot);
+                        }
+                    }
+                    self.write(Instruction::Move(0, r));
+                    if i + 1 == alts.len() {
+                        to_join.push(self.current_bb);
+                    } else {
+                        tests.push(self.current_bb);
                     }
                     self.move_forward();
-                    //self.immutable.insert(name.to_owned());
-                    //self.locals.insert(name.to_owned(), r as _);
                 }
-                let terminator_bb_id = self.current_bb;
-                for (branch, next) in branches {
-                    self.bbs[branch]
+                let join = self.current_bb;
+                for bb in tests {
+                    self.bbs[bb]
                         .instructions
-                        .push(Instruction::ConditionalBranch(
-                            0,
-                            next as _,
-                            terminator_bb_id as _,
-                        ));
+                        .push(Instruction::ConditionalBranch(0, join as _, bb as u16 + 1));
+                }
+                for bb in to_join {
+                    self.bbs[bb]
+                        .instructions
+                        .push(Instruction::Branch(join as _));
                 }
                 let r = self.new_reg();
                 self.write(Instruction::Move(r, 0));
                 Ok(r)
             }
+            PatternDecl::Range(lo, hi, inclusive) => {
+                let mut branches = vec![];
+                let lo = self.compile_pattern_const(lo)?;
+                let hi = self.compile_pattern_const(hi)?;
+                let r = self.new_reg();
+                self.write(Instruction::Binary(BinOp::GreaterOrEqual, r, val, lo));
+                self.pattern_check(r, &mut branches);
+                let op = if *inclusive {
+                    BinOp::LessOrEqual
+                } else {
+                    BinOp::Less
+                };
+                let r = self.new_reg();
+                self.write(Instruction::Binary(op, r, val, hi));
+                self.pattern_check(r, &mut branches);
+                Ok(self.pattern_join(branches))
+            }
+            PatternDecl::Bind(name, inner) => {
+                let r = self.compile_pattern(inner.pos, inner, val)?;
+                self.immutable.insert(name.to_owned());
+                let loc = self.new_reg();
+                self.write(Instruction::Move(loc, val));
+                self.locals.insert(name.to_owned(), loc as _);
+                Ok(r)
+            }
             PatternDecl::Array(patterns) => {
+                let rest = self.rest_position(patterns)?;
                 let mut branches = vec![];
+                let is_array = self.is_array(val);
+                self.pattern_check(is_array, &mut branches);
+                let len = self.array_length(val);
+                let len_ok = self.array_length_matches(len, patterns, rest);
+                self.pattern_check(len_ok, &mut branches);
                 for (i, pat) in patterns.iter().enumerate() {
+                    if let PatternDecl::Rest(name) = &pat.decl {
+                        if let Some(name) = name {
+                            let r = self.array_rest(val, len, i, patterns.len());
+                            self.immutable.insert(name.to_owned());
+                            self.locals.insert(name.to_owned(), r as _);
+                        }
+                        continue;
+                    }
+                    let index = self.array_pattern_index(i, rest, patterns.len(), len);
                     let r = self.new_reg();
-                    let r2 = self.new_reg();
-                    self.write(Instruction::LoadInt(r2, i as i32));
-                    self.write(Instruction::LoadByValue(r, val, r2));
+                    self.write(Instruction::LoadByValue(r, val, index));
                     let r = self.compile_pattern(pat.pos, pat, r)?;
-                    self.write(Instruction::Move(0, r));
-                    branches.push((self.current_bb, self.current_bb + 1));
-                    self.move_forward();
-                }
-                let terminator_bb_id = self.current_bb;
-                for (branch, next) in branches {
-                    self.bbs[branch]
-                        .instructions
-                        .push(Instruction::ConditionalBranch(
-                            0,
-                            next as _,
-                            terminator_bb_id as _,
-                        ));
+                    self.pattern_check(r, &mut branches);
                 }
+                Ok(self.pattern_join(branches))
+            }
+            PatternDecl::EmptyList => {
+                let mut branches = vec![];
+                let is_array = self.is_array(val);
+                self.pattern_check(is_array, &mut branches);
+                let len = self.array_length(val);
+                let zero = self.new_reg();
+                self.write(Instruction::LoadInt(zero, 0));
                 let r = self.new_reg();
-                self.write(Instruction::Move(r, 0));
-                Ok(r)
+                self.write(Instruction::Binary(BinOp::Equal, r, len, zero));
+                self.pattern_check(r, &mut branches);
+                Ok(self.pattern_join(branches))
+            }
+            PatternDecl::Cons(..) => {
+                // `a :: b :: rest` tests the heads by index and copies the
+                // elements after them once, a tail of `_` or `[]` is not
+                // copied at all. Binding the tail still copies it, so walking
+                // a list by matching on its tail again is quadratic.
+                let mut heads = vec![];
+                let mut tail = pat;
+                while let PatternDecl::Cons(head, rest) = &tail.decl {
+                    heads.push(head);
+                    tail = rest;
+                }
+                let mut branches = vec![];
+                let is_array = self.is_array(val);
+                self.pattern_check(is_array, &mut branches);
+                let len = self.array_length(val);
+                let count = self.new_reg();
+                self.write(Instruction::LoadInt(count, heads.len() as i32));
+                let len_ok = self.new_reg();
+                let cmp = if let PatternDecl::EmptyList = tail.decl {
+                    BinOp::Equal
+                } else {
+                    BinOp::GreaterOrEqual
+                };
+                self.write(Instruction::Binary(cmp, len_ok, len, count));
+                self.pattern_check(len_ok, &mut branches);
+
+                for (i, head) in heads.iter().enumerate() {
+                    let index = self.new_reg();
+                    self.write(Instruction::LoadInt(index, i as i32));
+                    let elem = self.new_reg();
+                    self.write(Instruction::LoadByValue(elem, val, index));
+                    let r = self.compile_pattern(head.pos, head, elem)?;
+                    self.pattern_check(r, &mut branches);
+                }
+                match tail.decl {
+                    PatternDecl::EmptyList | PatternDecl::Pass => (),
+                    _ => {
+                        let rest = self.array_slice(val, count, len);
+                        let r = self.compile_pattern(tail.pos, tail, rest)?;
+                        self.pattern_check(r, &mut branches);
+                    }
+                }
+                Ok(self.pattern_join(branches))
             }
             PatternDecl::Pass => {
                 self.write(Instruction::Branch(self.current_bb as u16 + 1));
@@ -1029,21 +2316,136 @@ impl Context {
                 self.write(Instruction::LoadTrue(r));
                 Ok(r)
             }
-            _ => unimplemented!(),
+            PatternDecl::Rest(_) => Err(MsgWithPos::new(
+                pat.pos,
+                Msg::Custom("`..` is only allowed inside array patterns".to_owned()),
+            )),
+            PatternDecl::Tuple(_) => Err(MsgWithPos::new(pat.pos, Msg::Unimplemented)),
         }
     }
+    /// Loads the value of a literal pattern.
+    fn compile_pattern_const(&mut self, pat: &Pattern) -> Result<u16, MsgWithPos> {
+        let r = self.new_reg();
+        match &pat.decl {
+            PatternDecl::ConstInt(i) => match i32::try_from(*i) {
+                Ok(i) => self.write(Instruction::LoadInt(r, i)),
+                Err(_) => self.write(Instruction::LoadNumber(r, (*i as f64).to_bits())),
+            },
+            PatternDecl::ConstFloat(f) => self.write(Instruction::LoadNumber(r, f.to_bits())),
+            PatternDecl::ConstChar(c) => {
+                let (gid, _) = self.global(&Global::Str(c.to_string()));
+                self.write(Instruction::LoadConst(r, gid as _));
+            }
+            _ => return Err(MsgWithPos::new(pat.pos, Msg::ConstValueExpected)),
+        }
+        Ok(r)
+    }
+
+    /// Position of the `..` element of an array pattern, if any.
+    fn rest_position(&self, patterns: &[Box<Pattern>]) -> Result<Option<usize>, MsgWithPos> {
+        let mut rest = None;
+        for (i, pat) in patterns.iter().enumerate() {
+            if let PatternDecl::Rest(_) = pat.decl {
+                if rest.is_some() {
+                    return Err(MsgWithPos::new(
+                        pat.pos,
+                        Msg::Custom("only one `..` is allowed in an array pattern".to_owned()),
+                    ));
+                }
+                rest = Some(i);
+            }
+        }
+        Ok(rest)
+    }
+
+    /// Index of element `i` of an array pattern with `count` elements.
+    /// Elements after a `..` are counted from the end of the array.
+    fn array_pattern_index(
+        &mut self,
+        i: usize,
+        rest: Option<usize>,
+        count: usize,
+        len: u16,
+    ) -> u16 {
+        let r = self.new_reg();
+        match rest {
+            Some(rest) if i > rest => {
+                let from_end = self.new_reg();
+                self.write(Instruction::LoadInt(from_end, (count - i) as i32));
+                self.write(Instruction::Binary(BinOp::Sub, r, len, from_end));
+            }
+            _ => self.write(Instruction::LoadInt(r, i as i32)),
+        }
+        r
+    }
+
+    /// Elements matched by the `..` at position `i` of an array pattern.
+    fn array_rest(&mut self, arr: u16, len: u16, i: usize, count: usize) -> u16 {
+        let from = self.new_reg();
+        self.write(Instruction::LoadInt(from, i as i32));
+        let after = self.new_reg();
+        self.write(Instruction::LoadInt(after, (count - i - 1) as i32));
+        let to = self.new_reg();
+        self.write(Instruction::Binary(BinOp::Sub, to, len, after));
+        self.array_slice(arr, from, to)
+    }
+
+    /// Tests the length `len` of an array against an array pattern: exactly
+    /// one element per item, or at least that many with a `..rest` item.
+    fn array_length_matches(
+        &mut self,
+        len: u16,
+        patterns: &[Box<Pattern>],
+        rest: Option<usize>,
+    ) -> u16 {
+        let fixed = self.new_reg();
+        let min_len = patterns.len() - rest.is_some() as usize;
+        self.write(Instruction::LoadInt(fixed, min_len as i32));
+        let len_ok = self.new_reg();
+        let cmp = if rest.is_some() {
+            BinOp::GreaterOrEqual
+        } else {
+            BinOp::Equal
+        };
+        self.write(Instruction::Binary(cmp, len_ok, len, fixed));
+        len_ok
+    }
+
     pub fn compile_var_pattern(
         &mut self,
         pos: Position,
-        pat: &Box<Pattern>,
+        pat: &Pattern,
         mutable: bool,
         r: u16,
     ) -> Result<(), MsgWithPos> {
         match &pat.decl {
             PatternDecl::Array(patterns) => {
+                let rest = self.rest_position(patterns)?;
+                let len = self.array_length(r);
+                let len_ok = self.array_length_matches(len, patterns, rest);
+                let fail_bb = self.current_bb as u16 + 1;
+                self.write(Instruction::ConditionalBranch(len_ok, fail_bb + 1, fail_bb));
+                self.move_forward();
+                let (gid, _) = self.global(&Global::Str(
+                    "array length does not match the pattern".to_owned(),
+                ));
+                let msg = self.new_reg();
+                self.write(Instruction::LoadConst(msg, gid as _));
+                self.write(Instruction::Throw(msg));
+                self.move_forward();
                 for (i, pat) in patterns.iter().enumerate() {
-                    let nr = self.new_reg();
-                    self.write(Instruction::LoadInt(nr, i as i32));
+                    if let PatternDecl::Rest(name) = &pat.decl {
+                        if let Some(name) = name {
+                            let val = self.array_rest(r, len, i, patterns.len());
+                            let name = Box::new(Pattern {
+                                decl: PatternDecl::Ident(name.to_owned()),
+                                pos: pat.pos,
+                            });
+                            self.compile_var_pattern(pat.pos, &name, mutable, val)?;
+                        }
+                        continue;
+                    }
+                    let nr = self.array_pattern_index(i, rest, patterns.len(), len);
                     let val = self.new_reg();
                     self.write(Instruction::LoadByValue(val, r, nr));
                     self.compile_var_pattern(pat.pos, pat, mutable, val)?;
@@ -1062,23 +2464,31 @@ impl Context {
             }
             PatternDecl::Record(fields) => {
                 for (name, p) in fields.iter() {
+                    let field = self.new_reg();
+                    let (id, _) = self.global(&Global::Str(name.to_owned()));
+                    self.write(Instruction::LoadById(field, r, id as _));
                     if let Some(pat) = p {
-                        return Err(MsgWithPos::new(
-                            pat.pos,
-                            Msg::Custom("unexpected pattern in variable declaration".to_owned()),
-                        ));
+                        self.compile_var_pattern(pat.pos, pat, mutable, field)?;
+                        continue;
                     }
                     if !mutable {
                         self.immutable.insert(name.clone());
+                    } else if self.immutable.contains(name) {
+                        self.immutable.remove(name);
                     }
                     let loc = self.new_reg();
-                    let field = self.new_reg();
-                    let (id, _) = self.global(&Global::Str(name.to_owned()));
-                    self.write(Instruction::LoadById(field, r, id as _));
                     self.write(Instruction::Move(loc, field));
                     self.locals.insert(name.to_owned(), loc as _);
                 }
             }
+            PatternDecl::Bind(name, inner) => {
+                let whole = Box::new(Pattern {
+                    decl: PatternDecl::Ident(name.to_owned()),
+                    pos: pat.pos,
+                });
+                self.compile_var_pattern(pos, &whole, mutable, r)?;
+                self.compile_var_pattern(inner.pos, inner, mutable, r)?;
+            }
             PatternDecl::Pass => (),
             _ => {
                 return Err(MsgWithPos::new(
@@ -1093,8 +2503,15 @@ impl Context {
     }
 
     pub fn compile_arg(&mut self, p: Position, arg: &Arg) -> Result<(), MsgWithPos> {
+        let r = self.new_reg();
+        self.write(Instruction::Pop(r));
+        self.bind_arg(p, arg, r)
+    }
+
+    /// Binds the parameter `arg` to the argument value in `r`.
+    fn bind_arg(&mut self, p: Position, arg: &Arg, r: u16) -> Result<(), MsgWithPos> {
         match arg {
-            Arg::Ident(mutable, name) => {
+            Arg::Ident(mutable, name) | Arg::Default(mutable, name, _) => {
                 if self.locals.contains_key(name) {
                     return Err(MsgWithPos::new(
                         p,
@@ -1105,48 +2522,86 @@ impl Context {
                     self.immutable.insert(name.clone());
                 }
 
-                let r = self.new_reg();
-                self.write(Instruction::Pop(r));
                 self.locals.insert(name.to_owned(), r as _);
                 Ok(())
             }
-            Arg::Record(rec) => {
-                let obj = self.new_reg();
-                self.write(Instruction::Pop(obj));
-                for item in rec {
-                    if self.locals.contains_key(item) {
+            Arg::Rest(name) => self.bind_arg(p, &Arg::Ident(false, name.clone()), r),
+            Arg::Record(_) | Arg::Array(_) => {
+                let pat = Box::new(Pattern {
+                    decl: match arg {
+                        Arg::Record(fields) => PatternDecl::Record(fields.clone()),
+                        Arg::Array(items) => PatternDecl::Array(items.clone()),
+                        _ => unreachable!(),
+                    },
+                    pos: p,
+                });
+                for item in pat.bindings() {
+                    if self.locals.contains_key(&item) {
                         return Err(MsgWithPos::new(
                             p,
                             Msg::Custom(format!("argument '{}' already defined", item)),
                         ));
                     }
-
-                    let field = self.new_reg();
-                    let (id, _) = self.global(&Global::Str(item.to_owned()));
-                    self.write(Instruction::LoadById(field, obj, id as _));
-                    self.locals.insert(item.to_owned(), field as _);
                 }
-                Ok(())
+                self.compile_var_pattern(p, &pat, true, r)
             }
-            Arg::Array(arr) => {
-                let obj = self.new_reg();
-                self.write(Instruction::Pop(obj));
-                for (i, item) in arr.iter().enumerate() {
-                    if self.locals.contains_key(item) {
-                        return Err(MsgWithPos::new(
-                            p,
-                            Msg::Custom(format!("argument '{}' already defined", item)),
+        }
+    }
+
+    /// Binds the parameters of a function with defaults or `...rest`. Such a
+    /// function accepts any number of arguments, so like the variadic
+    /// functions of the standard library it is declared without parameters
+    /// and reads the arguments from `Function.arguments()`. Only the rest
+    /// parameter copies them.
+    fn compile_variadic_args(&mut self, p: Position, params: &[Arg]) -> Result<(), MsgWithPos> {
+        let function = self.ld_static("Function");
+        let (gid, _) = self.global(&Global::Str("arguments".to_owned()));
+        let f = self.new_reg();
+        self.write(Instruction::LoadById(f, function, gid as _));
+        let args = self.new_reg();
+        self.write(Instruction::VirtCall(args, f, function, 0));
+        let count = self.array_length(args);
+        for (i, param) in params.iter().enumerate() {
+            let index = self.new_reg();
+            self.write(Instruction::LoadInt(index, i as i32));
+            let r = match param {
+                Arg::Rest(_) => self.array_slice(args, index, count),
+                Arg::Default(_, _, default) => {
+                    let r = self.new_reg();
+                    let given = self.new_reg();
+                    self.write(Instruction::Binary(BinOp::Less, given, index, count));
+                    let check_bb = self.current_bb;
+                    self.move_forward();
+                    self.write(Instruction::LoadByValue(r, args, index));
+                    let given_bb = self.current_bb;
+                    self.move_forward();
+                    let default_bb = self.current_bb;
+                    let value = self.compile(default, false)?;
+                    self.write(Instruction::Move(r, value));
+                    self.write(Instruction::Branch(self.current_bb as u16 + 1));
+                    self.move_forward();
+                    let end_bb = self.current_bb;
+                    self.bbs[given_bb]
+                        .instructions
+                        .push(Instruction::Branch(end_bb as _));
+                    self.bbs[check_bb]
+                        .instructions
+                        .push(Instruction::ConditionalBranch(
+                            given,
+                            check_bb as u16 + 1,
+                            default_bb as _,
                         ));
-                    }
-                    let field = self.new_reg();
-                    let id = self.new_reg();
-                    self.write(Instruction::LoadInt(id, i as i32));
-                    self.write(Instruction::LoadByValue(field, obj, id));
-                    self.locals.insert(item.to_owned(), field as _);
+                    r
                 }
-                Ok(())
-            }
+                _ => {
+                    let r = self.new_reg();
+                    self.write(Instruction::LoadByValue(r, args, index));
+                    r
+                }
+            };
+            self.bind_arg(p, param, r)?;
         }
+        Ok(())
     }
 
     pub fn compile_function(
@@ -1155,6 +2610,21 @@ impl Context {
         e: &Box<Expr>,
         vname: Option<String>,
     ) -> Result<u16, MsgWithPos> {
+        self.compile_function_with(params, e.pos, vname, |ctx| ctx.compile(e, true))
+    }
+
+    /// Compiles a function whose parameters are bound and whose body is
+    /// emitted by `body`, and returns the register holding the closure.
+    fn compile_function_with<F>(
+        &mut self,
+        params: &[Arg],
+        pos: Position,
+        vname: Option<String>,
+        mut body: F,
+    ) -> Result<u16, MsgWithPos>
+    where
+        F: FnMut(&mut Context) -> Result<u16, MsgWithPos>,
+    {
         let mut ctx = Context {
             immutable: LinkedHashSet::new(),
             g: self.g.clone(),
@@ -1172,13 +2642,23 @@ impl Context {
             current_bb: 0,
             used_upvars: LinkedHashMap::new(),
             loop_control_info: vec![],
+            try_stack: vec![],
+            optional_exits: vec![],
+            generator: None,
             cur_pos: (0, 0),
             cur_file: String::new(),
             regs: 33,
             parent: Some(DerefPointer::new(self)),
         };
-        for p in params.iter().rev() {
-            ctx.compile_arg(e.pos, p)?;
+        let variadic = params
+            .iter()
+            .any(|p| matches!(p, Arg::Default(..) | Arg::Rest(_)));
+        if variadic {
+            ctx.compile_variadic_args(pos, params)?;
+        } else {
+            for p in params.iter().rev() {
+                ctx.compile_arg(pos, p)?;
+            }
         }
         if vname.is_some() {
             self.global(&Global::Str(vname.as_ref().unwrap().to_owned()));
@@ -1192,7 +2672,7 @@ impl Context {
                 .insert(Global::Var(vname.as_ref().unwrap().to_owned()), gid as i32);
         }
         ctx.g.borrow_mut().table.push(Global::Func(gid as i32, -1));
-        let r = ctx.compile(e, true)?;
+        let r = body(&mut ctx)?;
         ctx.write(Instruction::Branch(ctx.current_bb as u16 + 1));
         ctx.move_forward();
         if r != 0 {
@@ -1217,7 +2697,7 @@ impl Context {
             ctx.bbs.clone(),
             ctx.pos.clone(),
             gid as i32,
-            params.len() as i32,
+            if variadic { 0 } else { params.len() as i32 },
             vname.unwrap_or(String::from("<anonymous>")),
         ));
 
@@ -1237,6 +2717,198 @@ impl Context {
             return Ok(r);
         }
     }
+    /// Compiles `function*`. Calling it binds the parameters and returns an
+    /// iterator whose `next()` runs `body` as a state machine: state 0
+    /// starts the body, state `i` resumes after the `i`th `yield` and -1
+    /// means the body returned.
+    fn compile_generator(
+        &mut self,
+        params: &[Arg],
+        body: &Expr,
+        vname: Option<String>,
+    ) -> Result<u16, MsgWithPos> {
+        self.compile_function_with(params, body.pos, vname, |ctx| {
+            let next = ctx.compile_function_with(&[], body.pos, None, |ctx| {
+                ctx.compile_generator_body(body)
+            })?;
+            let gen = ctx.new_object();
+            let state = ctx.new_reg();
+            ctx.write(Instruction::LoadInt(state, 0));
+            ctx.store_field(gen, state, "state");
+            let frame = ctx.new_array();
+            ctx.store_field(gen, frame, "frame");
+            let receiver = ctx.new_reg();
+            ctx.write(Instruction::LoadThis(receiver));
+            ctx.store_field(gen, receiver, "receiver");
+            ctx.store_field(gen, next, "next");
+            Ok(gen)
+        })
+    }
+
+    /// Emits the `next()` of a generator. The register saves of each
+    /// `yield` and the dispatch on the state are only known once the whole
+    /// body is compiled, so they are filled in afterwards.
+    fn compile_generator_body(&mut self, body: &Expr) -> Result<u16, MsgWithPos> {
+        let gen = self.new_reg();
+        self.write(Instruction::LoadThis(gen));
+        let frame = self.load_field(gen, "frame");
+        let state = self.load_field(gen, "state");
+        let receiver = self.load_field(gen, "receiver");
+        let dispatch_bb = self.current_bb;
+        self.move_forward();
+        let start_bb = self.current_bb;
+        self.generator = Some(GeneratorInfo {
+            gen,
+            receiver,
+            frame,
+            resumes: vec![],
+            saves: vec![],
+        });
+        let r = self.compile(body, false)?;
+        let r = self.generator_return(r);
+        self.write(Instruction::Return(Some(r)));
+        self.move_forward();
+        let info = self.generator.take().unwrap();
+
+        // Each `yield` saves the registers live where its state resumes, the
+        // ones set before the body are loaded again by every `next()`.
+        let live = live_in(&self.bbs);
+        let saved = info
+            .resumes
+            .iter()
+            .map(|&bb| {
+                live[bb]
+                    .iter()
+                    .cloned()
+                    .filter(|&r| r > receiver)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let slots = (0..saved.iter().map(|regs| regs.len()).max().unwrap_or(0))
+            .map(|i| {
+                let r = self.new_reg();
+                (r, i as i32)
+            })
+            .collect::<Vec<_>>();
+        for (&(bb, at), regs) in info.saves.iter().zip(saved.iter()) {
+            let mut saves = vec![];
+            for (&r, &(slot, i)) in regs.iter().zip(slots.iter()) {
+                saves.push(Instruction::LoadInt(slot, i));
+                saves.push(Instruction::StoreByValue(frame, slot, r));
+            }
+            self.bbs[bb].instructions.splice(at..at, saves);
+        }
+
+        let restore_bb = self.current_bb;
+        for (i, (&resume_bb, regs)) in info.resumes.iter().zip(saved.iter()).enumerate() {
+            let k = self.new_reg();
+            self.write(Instruction::LoadInt(k, i as i32 + 1));
+            let matches = self.new_reg();
+            self.write(Instruction::Binary(BinOp::Equal, matches, state, k));
+            self.write(Instruction::ConditionalBranch(
+                matches,
+                self.current_bb as u16 + 1,
+                self.current_bb as u16 + 2,
+            ));
+            self.move_forward();
+            for (&r, &(slot, i)) in regs.iter().zip(slots.iter()) {
+                self.write(Instruction::LoadInt(slot, i));
+                self.write(Instruction::LoadByValue(r, frame, slot));
+            }
+            self.write(Instruction::Branch(resume_bb as _));
+            self.move_forward();
+        }
+        let zero = self.new_reg();
+        let started = self.new_reg();
+        self.bbs[dispatch_bb].instructions.extend(vec![
+            Instruction::LoadInt(zero, 0),
+            Instruction::Binary(BinOp::Equal, started, state, zero),
+            Instruction::ConditionalBranch(started, start_bb as _, restore_bb as _),
+        ]);
+        // The body already returned.
+        let value = self.new_reg();
+        self.write(Instruction::LoadNull(value));
+        Ok(self.iter_result(true, value))
+    }
+
+    fn compile_yield(&mut self, p: Position, value: &Expr) -> Result<u16, MsgWithPos> {
+        if self.generator.is_none() {
+            return Err(MsgWithPos::new(
+                p,
+                Msg::Custom("`yield` is only allowed inside `function*`".to_owned()),
+            ));
+        }
+        if !self.try_stack.is_empty() {
+            // The handler would be gone when `next()` resumes.
+            return Err(MsgWithPos::new(
+                p,
+                Msg::Custom("`yield` is not allowed inside `try` or after `defer`".to_owned()),
+            ));
+        }
+        let mut value = self.compile(value, false)?;
+        if value == 0 {
+            value = self.new_reg();
+            self.write(Instruction::LoadNull(value));
+        }
+        let save = (self.current_bb, self.get_current_bb().instructions.len());
+        let gen = self.generator.as_ref().unwrap().gen;
+        let state = self.new_reg();
+        let resume = self.generator.as_ref().unwrap().resumes.len() + 1;
+        self.write(Instruction::LoadInt(state, resume as i32));
+        self.store_field(gen, state, "state");
+        let r = self.iter_result(false, value);
+        self.write(Instruction::Return(Some(r)));
+        self.move_forward();
+        let info = self.generator.as_mut().unwrap();
+        info.saves.push(save);
+        info.resumes.push(self.current_bb);
+        let r = self.new_reg();
+        self.write(Instruction::LoadNull(r));
+        Ok(r)
+    }
+
+    /// Marks the generator as done and emits the last `{done, value}`.
+    fn generator_return(&mut self, value: u16) -> u16 {
+        let gen = self.generator.as_ref().unwrap().gen;
+        let state = self.new_reg();
+        self.write(Instruction::LoadInt(state, -1));
+        self.store_field(gen, state, "state");
+        let value = if value == 0 {
+            let r = self.new_reg();
+            self.write(Instruction::LoadNull(r));
+            r
+        } else {
+            value
+        };
+        self.iter_result(true, value)
+    }
+
+    /// Loads `this` into `r`. In a generator body `this` is the receiver of
+    /// the generator function, not the generator object `next()` runs on.
+    fn load_this(&mut self, r: u16) {
+        match &self.generator {
+            Some(info) => {
+                let receiver = info.receiver;
+                self.write(Instruction::Move(r, receiver));
+            }
+            None => self.write(Instruction::LoadThis(r)),
+        }
+    }
+
+    /// Emits `new { done, value }`, what `next()` of an iterator returns.
+    fn iter_result(&mut self, done: bool, value: u16) -> u16 {
+        let r = self.new_object();
+        let flag = self.new_reg();
+        if done {
+            self.write(Instruction::LoadTrue(flag));
+        } else {
+            self.write(Instruction::LoadFalse(flag));
+        }
+        self.store_field(r, flag, "done");
+        self.store_field(r, value, "value");
+        r
+    }
+
     fn ident(&mut self, name: &str) -> u16 {
         let s: &str = name;
         if self.locals.contains_key(s) {
@@ -1269,6 +2941,8 @@ impl Context {
             objects: LinkedHashMap::new(),
             functions: vec![],
             table: vec![],
+            signatures: HashMap::new(),
+            atoms: LinkedHashSet::new(),
         };
         Self {
             g: Rc::new(RefCell::new(g)),
@@ -1285,6 +2959,9 @@ impl Context {
             current_bb: 0,
             labels: HashMap::new(),
             loop_control_info: vec![],
+            try_stack: vec![],
+            optional_exits: vec![],
+            generator: None,
             cur_pos: (0, 0),
             cur_file: String::new(),
             pos: vec![],
@@ -1297,6 +2974,124 @@ impl Context {
     pub fn finalize(&mut self, _tail: bool, _name: String) {}
 }
 
+/// The instruction for an arithmetic, shift or bitwise operator.
+fn binop(op: &str) -> Option<BinOp> {
+    Some(match op {
+        "+" => BinOp::Add,
+        "-" => BinOp::Sub,
+        "*" => BinOp::Mul,
+        "/" => BinOp::Div,
+        "%" => BinOp::Mod,
+        "<<" => BinOp::Lsh,
+        ">>" => BinOp::Rsh,
+        ">>>" => BinOp::URsh,
+        "&" => BinOp::And,
+        "|" => BinOp::Or,
+        "^" => BinOp::Xor,
+        _ => return None,
+    })
+}
+
+/// The registers `ins` reads and the one it writes.
+fn instruction_regs(ins: &Instruction) -> (Vec<u16>, Option<u16>) {
+    use Instruction::*;
+    match *ins {
+        LoadNull(d)
+        | LoadUndefined(d)
+        | LoadInt(d, _)
+        | LoadNumber(d, _)
+        | LoadTrue(d)
+        | LoadFalse(d)
+        | LoadStaticById(d, _)
+        | LoadConst(d, _)
+        | LoadUpvalue(d, _)
+        | LoadThis(d)
+        | Pop(d)
+        | CatchBlock(d, _) => (vec![], Some(d)),
+        MakeEnv(d, _) => (vec![d], Some(d)),
+        LoadById(d, o, _) | Move(d, o) | Unary(_, d, o) | New(d, o, _) | Call(d, o, _) => {
+            (vec![o], Some(d))
+        }
+        LoadByValue(d, o, k) | Binary(_, d, o, k) | VirtCall(d, o, k, _) => (vec![o, k], Some(d)),
+        StoreById(o, v, _) => (vec![o, v], None),
+        StoreByValue(o, k, v) => (vec![o, k, v], None),
+        StoreStaticById(v, _)
+        | StoreUpvalue(v, _)
+        | SetThis(v)
+        | ConditionalBranch(v, _, _)
+        | BranchIfTrue(v, _)
+        | BranchIfFalse(v, _)
+        | Push(v)
+        | Throw(v)
+        | Return(Some(v)) => (vec![v], None),
+        TailCall(_, f, _) => (vec![f], None),
+        Swap(a, b) => (vec![a, b], Some(a)),
+        _ => (vec![], None),
+    }
+}
+
+/// The registers read before being written on some path from the start of
+/// each block.
+fn live_in(bbs: &[BasicBlock]) -> Vec<BTreeSet<u16>> {
+    let successors = bbs
+        .iter()
+        .map(|bb| {
+            let mut next = vec![];
+            for ins in bb.instructions.iter() {
+                match *ins {
+                    Instruction::Branch(t)
+                    | Instruction::BranchIfTrue(_, t)
+                    | Instruction::BranchIfFalse(_, t)
+                    | Instruction::CatchBlock(_, t) => next.push(t as usize),
+                    Instruction::ConditionalBranch(_, t, f) => {
+                        next.extend(vec![t as usize, f as usize])
+                    }
+                    _ => (),
+                }
+            }
+            next
+        })
+        .collect::<Vec<_>>();
+    let mut live = vec![BTreeSet::new(); bbs.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, bb) in bbs.iter().enumerate().rev() {
+            let mut regs = BTreeSet::new();
+            for &s in successors[i].iter() {
+                regs.extend(live.get(s).into_iter().flatten().cloned());
+            }
+            for ins in bb.instructions.iter().rev() {
+                let (uses, def) = instruction_regs(ins);
+                if let Some(d) = def {
+                    regs.remove(&d);
+                }
+                regs.extend(uses.into_iter().filter(|&r| r != 0));
+            }
+            if regs != live[i] {
+                live[i] = regs;
+                changed = true;
+            }
+        }
+    }
+    live
+}
+
+fn is_spread(e: &Expr) -> bool {
+    matches!(e.expr, ExprKind::Spread(_))
+}
+
+/// Whether `e` is the part of an optional chain that follows a `?.`.
+fn in_safe_chain(e: &Expr) -> bool {
+    match &e.expr {
+        ExprKind::SafeNav(_) => true,
+        ExprKind::Access(object, _)
+        | ExprKind::ArrayIndex(object, _)
+        | ExprKind::Call(object, _) => in_safe_chain(object),
+        _ => false,
+    }
+}
+
 pub fn compile(ast: Vec<Box<Expr>>, no_std: bool) -> Result<Context, MsgWithPos> {
     let mut ctx = Context::new();
     let ast = Box::new(Expr {
@@ -1310,6 +3105,9 @@ pub fn compile(ast: Vec<Box<Expr>>, no_std: bool) -> Result<Context, MsgWithPos>
         ctx.write(Instruction::Call(r2, r1, 0));
     }
     ctx.global(&Global::Str("<anonymous>".to_owned()));
+    if let ExprKind::Block(items) = &ast.expr {
+        ctx.declare_signatures(items);
+    }
     let r = ctx.compile(&ast, false)?;
     ctx.write(Instruction::Branch(ctx.current_bb as u16 + 1));
     ctx.move_forward();
@@ -1320,6 +3118,7 @@ pub fn compile(ast: Vec<Box<Expr>>, no_std: bool) -> Result<Context, MsgWithPos>
         ctx.write(Instruction::LoadNull(r));
         ctx.write(Instruction::Return(Some(r)));
     }
+    ctx.intern_atoms();
     ctx.global(&Global::Str("main".to_owned()));
     Ok(ctx)
 }
@@ -1402,3 +3201,890 @@ pub fn disassemble_module(module: &Arc<Module>) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compiles the statements of `code` into the blocks of a context.
+    fn compile_code(code: &str) -> Context {
+        let mut ast = vec![];
+        Parser::new(Reader::from_string(code), &mut ast)
+            .parse()
+            .unwrap();
+        let mut ctx = Context::new();
+        ctx.declare_signatures(&ast);
+        for e in ast.iter() {
+            ctx.compile(e, false).unwrap();
+        }
+        ctx
+    }
+
+    /// Compiles `code` and tells whether any emitted instruction satisfies `f`.
+    fn emits(code: &str, f: fn(&Instruction) -> bool) -> bool {
+        let ctx = compile_code(code);
+        ctx.bbs.iter().any(|bb| bb.instructions.iter().any(f))
+    }
+
+    /// Instructions run from the start of block `bb`, following unconditional
+    /// branches, and the blocks they are in.
+    fn run_from(bbs: &[BasicBlock], mut bb: usize) -> (Vec<Instruction>, Vec<usize>) {
+        let mut code = vec![];
+        let mut visited = vec![];
+        loop {
+            visited.push(bb);
+            for i in bbs[bb].instructions.iter() {
+                code.push(i.clone());
+            }
+            match bbs[bb].instructions.last() {
+                Some(Instruction::Branch(next)) => bb = *next as usize,
+                _ => return (code, visited),
+            }
+        }
+    }
+
+    /// Index of the first block loading the static `name`.
+    fn block_loading(ctx: &Context, name: &str) -> usize {
+        let id = ctx.g.borrow().globals[&Global::Str(name.into())] as u32;
+        ctx.bbs
+            .iter()
+            .position(|bb| {
+                bb.instructions
+                    .iter()
+                    .any(|i| matches!(i, Instruction::LoadStaticById(_, g) if *g == id))
+            })
+            .unwrap()
+    }
+
+    /// Index of the first block loading the field `name` of some object.
+    fn block_accessing(ctx: &Context, name: &str) -> usize {
+        let id = ctx.g.borrow().globals[&Global::Str(name.into())] as u32;
+        ctx.bbs
+            .iter()
+            .position(|bb| {
+                bb.instructions
+                    .iter()
+                    .any(|i| matches!(i, Instruction::LoadById(_, _, g) if *g == id))
+            })
+            .unwrap()
+    }
+
+    fn compile_module(code: &str) -> Context {
+        let mut ast = vec![];
+        Parser::new(Reader::from_string(code), &mut ast)
+            .parse()
+            .unwrap();
+        compile(ast, true).unwrap()
+    }
+
+    fn compile_error(code: &str) -> Msg {
+        let mut ast = vec![];
+        Parser::new(Reader::from_string(code), &mut ast)
+            .parse()
+            .unwrap();
+        match compile(ast, true) {
+            Ok(_) => panic!("`{}` compiled", code),
+            Err(e) => e.msg,
+        }
+    }
+
+    #[test]
+    fn keyword_args_checked() {
+        let def = "function draw(shape, {x, y}) { x }\n";
+        assert_eq!(
+            compile_error(&format!("{}draw(1, x: 1, z: 2)", def)),
+            Msg::UnknownKeywordArg("draw".into(), "z".into())
+        );
+        assert_eq!(
+            compile_error(&format!("{}draw(1, x: 1)", def)),
+            Msg::MissingKeywordArg("draw".into(), "y".into())
+        );
+        assert_eq!(
+            compile_error(&format!("{}draw(x: 1, y: 2)", def)),
+            Msg::NoKeywordArgs("draw".into())
+        );
+        // Calls are checked against functions declared after them.
+        assert_eq!(
+            compile_error(&format!("draw(1, x: 1)\n{}", def)),
+            Msg::MissingKeywordArg("draw".into(), "y".into())
+        );
+        // A method is not the free function of the same name.
+        compile_code(&format!(
+            "{}let shapes = new {{}}\nshapes.draw = function draw(shape) {{ shape }}\ndraw(1, x: 1, y: 2)",
+            def
+        ));
+        compile_code(
+            "let shapes = new {}\nshapes.draw = function draw(shape) { shape }\ndraw(1, z: 2)",
+        );
+    }
+
+    #[test]
+    fn generator_saves_live_registers_at_yield() {
+        let ctx = compile_module(
+            "function* g(n) {\n var i = n\n yield i\n i = i + 1\n yield this.step\n}",
+        );
+        let g = ctx.g.borrow();
+        let next = &g.functions.iter().find(|f| f.4 == "<anonymous>").unwrap().0;
+        assert!(matches!(
+            next[0].instructions.last(),
+            Some(Instruction::ConditionalBranch(..))
+        ));
+        let saves = next
+            .iter()
+            .filter(|bb| {
+                bb.instructions
+                    .iter()
+                    .any(|i| matches!(i, Instruction::LoadFalse(_)))
+            })
+            .map(|bb| {
+                bb.instructions
+                    .iter()
+                    .filter(|i| matches!(i, Instruction::StoreByValue(..)))
+                    .count()
+            })
+            .collect::<Vec<_>>();
+        // Only `i` is still needed after the first `yield`, nothing after the
+        // second.
+        assert_eq!(saves, vec![1, 0]);
+        let restores = next
+            .iter()
+            .flat_map(|bb| bb.instructions.iter())
+            .filter(|i| matches!(i, Instruction::LoadByValue(..)))
+            .count();
+        assert_eq!(restores, 1);
+        // `this` is the receiver, loaded from the generator once per `next()`.
+        let loads_this = next
+            .iter()
+            .flat_map(|bb| bb.instructions.iter())
+            .filter(|i| matches!(i, Instruction::LoadThis(_)))
+            .count();
+        assert_eq!(loads_this, 1);
+        assert!(!g
+            .globals
+            .keys()
+            .any(|k| *k == Global::Str("r33".to_owned())));
+    }
+
+    #[test]
+    fn yield_in_call_arguments_pushes_after_resume() {
+        let ctx = compile_module("function* g() { f(1, yield 2) }");
+        let g = ctx.g.borrow();
+        let next = &g.functions.iter().find(|f| f.4 == "<anonymous>").unwrap().0;
+        let code = next
+            .iter()
+            .flat_map(|bb| bb.instructions.iter())
+            .collect::<Vec<_>>();
+        let call = code
+            .iter()
+            .position(|i| matches!(i, Instruction::Call(_, _, 2)))
+            .unwrap();
+        let (one, sent) = match (code[call - 2], code[call - 1]) {
+            (Instruction::Push(one), Instruction::Push(sent)) => (*one, *sent),
+            i => panic!("pushed arguments expected, got {:?}", i),
+        };
+        // Nothing is on the stack when the body returns at the `yield`, the
+        // first argument is saved in the frame and restored on resume.
+        let yielded = code
+            .iter()
+            .position(|i| matches!(i, Instruction::Return(Some(_))))
+            .unwrap();
+        assert!(!code[..yielded]
+            .iter()
+            .any(|i| matches!(i, Instruction::Push(_))));
+        assert!(code
+            .iter()
+            .any(|i| matches!(i, Instruction::LoadByValue(r, ..) if *r == one)));
+        assert_ne!(one, sent);
+    }
+
+    #[test]
+    fn async_calls_run_body_in_task() {
+        let ctx = compile_module(
+            "async function fetch(x) { x + 1 }\nasync function main() { await fetch(1) }",
+        );
+        let g = ctx.g.borrow();
+        // The functions calling the intrinsic `name` with one argument.
+        let callers = |name: &str| {
+            let gid = g.globals[&Global::Var(name.to_owned())];
+            g.functions
+                .iter()
+                .filter(|f| {
+                    f.0.iter().any(|bb| {
+                        bb.instructions.windows(2).any(|w| match (&w[0], &w[1]) {
+                            (Instruction::LoadConst(r, id), Instruction::Call(_, f, 1)) => {
+                                r == f && *id == gid as u32
+                            }
+                            _ => false,
+                        })
+                    })
+                })
+                .map(|f| f.4.clone())
+                .collect::<Vec<_>>()
+        };
+        // Calling an async function hands its body to `__async`, the body
+        // itself runs in the task.
+        assert_eq!(callers("__async"), vec!["fetch", "main"]);
+        assert_eq!(callers("__await"), vec!["<anonymous>"]);
+        let fetch = &g.functions.iter().find(|f| f.4 == "fetch").unwrap().0;
+        assert!(!fetch
+            .iter()
+            .flat_map(|bb| bb.instructions.iter())
+            .any(|i| matches!(i, Instruction::Binary(BinOp::Add, ..))));
+    }
+
+    #[test]
+    fn receive_blocks_terminated() {
+        let mut ast = vec![];
+        let code = "receive { [:chunk, x] when x > 0 -> x, :stop -> 0, after 10 -> 1 }";
+        Parser::new(Reader::from_string(code), &mut ast)
+            .parse()
+            .unwrap();
+        let mut ctx = Context::new();
+        ctx.compile(&ast[0], false).unwrap();
+        let (last, blocks) = ctx.bbs.split_last().unwrap();
+        assert!(last.instructions.is_empty());
+        for bb in blocks {
+            assert!(
+                matches!(
+                    bb.instructions.last(),
+                    Some(Instruction::Branch(_)) | Some(Instruction::ConditionalBranch(..))
+                ),
+                "block {} is not terminated",
+                bb.index
+            );
+        }
+    }
+
+    #[test]
+    fn receive_cancels_timer_and_drops_stale_timeouts() {
+        let ctx = compile_code("receive { :stop -> 0, after 10 -> 1 }");
+        let g = ctx.g.borrow();
+        let var = |name: &str| g.globals[&Global::Var(name.to_owned())] as u32;
+        let loads = |bb: &BasicBlock, gid: u32| {
+            bb.instructions
+                .iter()
+                .position(|i| matches!(i, Instruction::LoadConst(_, id) if *id == gid))
+        };
+        let loop_bb = ctx
+            .bbs
+            .iter()
+            .position(|bb| {
+                bb.instructions
+                    .iter()
+                    .any(|i| matches!(i, Instruction::VirtCall(_, _, _, 0)))
+            })
+            .unwrap();
+        // A stale timeout goes straight back to `Process.recv()`, it is
+        // neither matched nor set aside.
+        assert!(loads(&ctx.bbs[loop_bb], var("__is_stale")).is_some());
+        assert!(matches!(
+            ctx.bbs[loop_bb].instructions.last(),
+            Some(Instruction::ConditionalBranch(_, t, _)) if *t as usize == loop_bb
+        ));
+        // The matching arm cancels the timer before requeueing the messages
+        // set aside, the `after` arm does not need to.
+        let cancelled = g.globals[&Global::Str("cancelled".to_owned())] as u32;
+        let requeues = ctx
+            .bbs
+            .iter()
+            .filter_map(|bb| {
+                loads(bb, var("__requeue")).map(|at| {
+                    bb.instructions[..at]
+                        .iter()
+                        .any(|i| matches!(i, Instruction::StoreById(_, _, id) if *id == cancelled))
+                })
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(requeues, vec![false, true]);
+    }
+
+    #[test]
+    fn actors_spawn_receive_loop() {
+        let actor = |supervised: &str| {
+            compile_module(&format!(
+                "actor Counter(start) {} {{\n state n = start\n on :inc -> n = n + 1\n on [:get, from] -> reply(from, n)\n}}",
+                supervised
+            ))
+        };
+        for &supervised in [false, true].iter() {
+            let ctx = actor(if supervised { "supervised" } else { "" });
+            let g = ctx.g.borrow();
+            let field = |name: &str| g.globals.get(&Global::Str(name.to_owned())).cloned();
+            let reads = |code: &[BasicBlock], name: &str| {
+                code.iter().flat_map(|bb| bb.instructions.iter()).any(|i| {
+                    matches!(i, Instruction::LoadById(_, _, id) if Some(*id as i32) == field(name))
+                })
+            };
+            let counter = &g.functions.iter().find(|f| f.4 == "Counter").unwrap().0;
+            assert!(reads(counter, "spawn"));
+            // Only a supervised actor needs to know its parent.
+            assert_eq!(reads(counter, "current"), supervised);
+            let process = &g
+                .functions
+                .iter()
+                .find(|f| f.4 == "<anonymous>" && reads(&f.0, "recv"))
+                .unwrap()
+                .0;
+            let catches = process
+                .iter()
+                .flat_map(|bb| bb.instructions.iter())
+                .any(|i| matches!(i, Instruction::CatchBlock(..)));
+            assert_eq!(catches, supervised);
+        }
+    }
+
+    #[test]
+    fn par_map_expanded() {
+        fn calls_par_map(i: &Instruction) -> bool {
+            matches!(i, Instruction::Call(_, _, 3))
+        }
+        assert!(emits("par_map(xs, f, 4)", calls_par_map));
+        // A variable named `par_map` is called as is, `__par_map` is never loaded.
+        assert!(!emits("let par_map = 1\npar_map(xs, f, 4)", |i| {
+            matches!(i, Instruction::LoadConst(..))
+        }));
+        assert!(matches!(compile_error("par_map(xs, f)"), Msg::Custom(_)));
+        // Neither does a function declared later, but a method does not hide it.
+        let uses_par_map = |code: &str| {
+            let ctx = compile_code(code);
+            let g = ctx.g.borrow();
+            g.globals.contains_key(&Global::Var("__par_map".to_owned()))
+        };
+        assert!(!uses_par_map(
+            "par_map(xs, f, 4)\nfunction par_map(a, b, c) { a }"
+        ));
+        assert!(uses_par_map(
+            "let pool = new {}\npool.par_map = function par_map(a, b, c) { a }\npar_map(xs, f, 4)"
+        ));
+    }
+
+    #[test]
+    fn for_binder_leaves_outer_var_mutable() {
+        compile_code("function f() { var i = 0\n for i in 0..3 { i }\n i = 5 }");
+        assert_eq!(
+            compile_error("function f() { var i = 0\n for i in 0..3 { i = 5 } }"),
+            Msg::LetReassigned
+        );
+    }
+
+    #[test]
+    fn if_let_binder_leaves_outer_var_mutable() {
+        compile_code("function f(y) { var x = 0\n if let x = y { }\n x = 1 }");
+        compile_code("function f(y) { var x = 0\n while let x = y { }\n x = 1 }");
+    }
+
+    #[test]
+    fn catch_binder_leaves_outer_var_mutable() {
+        compile_code("function f() { var e = 0\n try { } catch e { }\n e = 1 }");
+    }
+
+    #[test]
+    fn comprehension_scoped() {
+        let mut ast = vec![];
+        Parser::new(Reader::from_string("[x for x in xs if x > 1]"), &mut ast)
+            .parse()
+            .unwrap();
+        let mut ctx = Context::new();
+        ctx.compile(&ast[0], false).unwrap();
+        assert!(ctx.locals.is_empty());
+        compile_code("function f(xs) { var x = 0\n x = [x + 1 for x in xs] }");
+    }
+
+    #[test]
+    fn yield_outside_generator() {
+        assert!(matches!(
+            compile_error("function f() { yield 1 }"),
+            Msg::Custom(_)
+        ));
+        assert!(matches!(
+            compile_error("function* f() { try { yield 1 } finally { 2 } }"),
+            Msg::Custom(_)
+        ));
+    }
+
+    /// Code and a test of the instruction it must emit.
+    type Case = (&'static str, fn(&Instruction) -> bool);
+
+    #[test]
+    fn binop_mapping() {
+        let cases: &[Case] = &[
+            ("a + b", |i| {
+                matches!(i, Instruction::Binary(BinOp::Add, ..))
+            }),
+            ("a - b", |i| {
+                matches!(i, Instruction::Binary(BinOp::Sub, ..))
+            }),
+            ("a * b", |i| {
+                matches!(i, Instruction::Binary(BinOp::Mul, ..))
+            }),
+            ("a / b", |i| {
+                matches!(i, Instruction::Binary(BinOp::Div, ..))
+            }),
+            ("a % b", |i| {
+                matches!(i, Instruction::Binary(BinOp::Mod, ..))
+            }),
+            ("a << b", |i| {
+                matches!(i, Instruction::Binary(BinOp::Lsh, ..))
+            }),
+            ("a >> b", |i| {
+                matches!(i, Instruction::Binary(BinOp::Rsh, ..))
+            }),
+            ("a >>> b", |i| {
+                matches!(i, Instruction::Binary(BinOp::URsh, ..))
+            }),
+            ("a & b", |i| {
+                matches!(i, Instruction::Binary(BinOp::And, ..))
+            }),
+            ("a | b", |i| matches!(i, Instruction::Binary(BinOp::Or, ..))),
+            ("a ^ b", |i| {
+                matches!(i, Instruction::Binary(BinOp::Xor, ..))
+            }),
+            ("a == b", |i| {
+                matches!(i, Instruction::Binary(BinOp::Equal, ..))
+            }),
+            ("a != b", |i| {
+                matches!(i, Instruction::Binary(BinOp::NotEqual, ..))
+            }),
+            ("a < b", |i| {
+                matches!(i, Instruction::Binary(BinOp::Less, ..))
+            }),
+            ("a <= b", |i| {
+                matches!(i, Instruction::Binary(BinOp::LessOrEqual, ..))
+            }),
+            ("a > b", |i| {
+                matches!(i, Instruction::Binary(BinOp::Greater, ..))
+            }),
+            ("a >= b", |i| {
+                matches!(i, Instruction::Binary(BinOp::GreaterOrEqual, ..))
+            }),
+            ("var a = 1\na <<= 2", |i| {
+                matches!(i, Instruction::Binary(BinOp::Lsh, ..))
+            }),
+            ("var a = 1\na >>= 2", |i| {
+                matches!(i, Instruction::Binary(BinOp::Rsh, ..))
+            }),
+            ("var a = 1\na >>>= 2", |i| {
+                matches!(i, Instruction::Binary(BinOp::URsh, ..))
+            }),
+            ("var a = 1\na &= 2", |i| {
+                matches!(i, Instruction::Binary(BinOp::And, ..))
+            }),
+            ("var a = 1\na |= 2", |i| {
+                matches!(i, Instruction::Binary(BinOp::Or, ..))
+            }),
+            ("var a = 1\na ^= 2", |i| {
+                matches!(i, Instruction::Binary(BinOp::Xor, ..))
+            }),
+        ];
+        for (code, check) in cases.iter() {
+            assert!(emits(code, *check), "wrong instruction for `{}`", code);
+        }
+    }
+
+    #[test]
+    fn unknown_binop() {
+        let pos = Position::new(1, 1);
+        let operand = |name: &str| {
+            Box::new(Expr {
+                pos,
+                expr: ExprKind::Ident(name.to_owned()),
+            })
+        };
+        let e = Expr {
+            pos,
+            expr: ExprKind::BinOp(operand("a"), "<=>".to_owned(), operand("b")),
+        };
+        assert_eq!(
+            Context::new().compile(&e, false).unwrap_err().msg,
+            Msg::Custom("unknown binary operator `<=>`".into())
+        );
+    }
+
+    #[test]
+    fn bitwise_not() {
+        assert!(emits("~a", |i| matches!(i, Instruction::LoadInt(_, -1))));
+        assert!(emits("~a", |i| matches!(
+            i,
+            Instruction::Binary(BinOp::Xor, ..)
+        )));
+    }
+
+    #[test]
+    fn atoms_interned_apart_from_strings() {
+        let ctx = compile_module("let a = :ok\nlet b = \":ok\"\na == :ok");
+        let g = ctx.g.borrow();
+        assert_eq!(g.atoms.iter().collect::<Vec<_>>(), vec!["ok"]);
+        let atom = g.globals[&Global::Str("atom:ok".into())] as u32;
+        let string = g.globals[&Global::Str(":ok".into())] as u32;
+        // The module starts by binding the atom, and `:ok` then loads it
+        // rather than the string.
+        let entry = &ctx.bbs[0].instructions;
+        assert!(matches!(entry[0], Instruction::LoadStaticById(_, id) if id == atom));
+        assert!(entry
+            .iter()
+            .any(|i| matches!(i, Instruction::StoreStaticById(_, id) if *id == atom)));
+        let loads = |id: u32| {
+            ctx.bbs
+                .iter()
+                .flat_map(|bb| bb.instructions.iter())
+                .filter(|i| matches!(i, Instruction::LoadStaticById(_, g) if *g == id))
+                .count()
+        };
+        assert_eq!(loads(atom), 3);
+        assert_eq!(loads(string), 0);
+    }
+
+    #[test]
+    fn array_pattern_checks_type_before_length() {
+        let ctx = compile_code("match 1 { [a, b] -> a, _ -> 0 }");
+        let check = block_loading(&ctx, "instanceof");
+        let len = block_accessing(&ctx, "length");
+        // Matching a non-array fails before asking it for its length.
+        match ctx.bbs[check].instructions.last() {
+            Some(Instruction::ConditionalBranch(_, next, fail)) => {
+                assert_eq!(*next as usize, len);
+                assert!(*fail as usize > len);
+            }
+            i => panic!("type check ends with {:?}", i),
+        }
+    }
+
+    #[test]
+    fn array_declarations_check_length() {
+        for (code, cmp, count) in [
+            ("let [a, b] = xs", BinOp::Equal, 2),
+            ("let [a, ..r, b] = xs", BinOp::GreaterOrEqual, 2),
+        ]
+        .iter()
+        {
+            let ctx = compile_code(code);
+            let code = ctx.bbs[block_accessing(&ctx, "length")]
+                .instructions
+                .clone();
+            let fixed = code
+                .iter()
+                .find_map(|i| match i {
+                    Instruction::LoadInt(r, n) if n == count => Some(*r),
+                    _ => None,
+                })
+                .unwrap();
+            let len_ok = code
+                .iter()
+                .find_map(|i| match i {
+                    Instruction::Binary(op, r, _, b) if op == cmp && *b == fixed => Some(*r),
+                    _ => None,
+                })
+                .unwrap();
+            // A mismatch throws before anything is bound.
+            match code.last() {
+                Some(Instruction::ConditionalBranch(r, _, fail)) if *r == len_ok => {
+                    assert!(matches!(
+                        ctx.bbs[*fail as usize].instructions.last(),
+                        Some(Instruction::Throw(_))
+                    ))
+                }
+                i => panic!("length check ends with {:?}", i),
+            }
+        }
+    }
+
+    #[test]
+    fn cons_patterns_check_type_and_copy_tail_once() {
+        for code in [
+            "match 1 { [] -> 0, _ -> 1 }",
+            "match 1 { x :: _ -> x, _ -> 0 }",
+        ]
+        .iter()
+        {
+            let ctx = compile_code(code);
+            assert!(
+                block_loading(&ctx, "instanceof") < block_accessing(&ctx, "length"),
+                "`{}` asks a non-array for its length",
+                code
+            );
+        }
+        // Only the elements after both heads are copied, in a single loop
+        // pushing onto a new array.
+        let ctx = compile_code("match 1 { a :: b :: rest -> rest, _ -> 0 }");
+        let push = ctx.g.borrow().globals[&Global::Str("push".into())] as u32;
+        let copies = ctx
+            .bbs
+            .iter()
+            .flat_map(|bb| bb.instructions.iter())
+            .filter(|i| matches!(i, Instruction::LoadById(_, _, id) if *id == push))
+            .count();
+        assert_eq!(copies, 1);
+        // Nothing is copied for a tail that is ignored.
+        let ctx = compile_code("match 1 { a :: b :: _ -> a, _ -> 0 }");
+        assert!(!ctx
+            .g
+            .borrow()
+            .globals
+            .contains_key(&Global::Str("push".into())));
+    }
+
+    #[test]
+    fn int_patterns_out_of_i32_range() {
+        let big = (-3_000_000_000f64).to_bits();
+        for code in [
+            "match 1 { -3000000000 -> 1, _ -> 0 }",
+            "match 1 { -3000000000..0 -> 1, _ -> 0 }",
+        ]
+        .iter()
+        {
+            let ctx = compile_code(code);
+            let instructions = || ctx.bbs.iter().flat_map(|bb| bb.instructions.iter());
+            assert!(instructions().any(|i| matches!(i, Instruction::LoadNumber(_, n) if *n == big)));
+            assert!(!instructions().any(|i| matches!(i, Instruction::LoadInt(_, 1294967296))));
+        }
+    }
+
+    #[test]
+    fn range_patterns_test_both_bounds() {
+        let ctx = compile_code("match 'c' { 'a'..='z' -> 1, _ -> 0 }");
+        let block = |f: fn(&Instruction) -> bool| {
+            ctx.bbs
+                .iter()
+                .position(|bb| bb.instructions.iter().any(f))
+                .unwrap()
+        };
+        let lo = block(|i| matches!(i, Instruction::Binary(BinOp::GreaterOrEqual, ..)));
+        let hi = block(|i| matches!(i, Instruction::Binary(BinOp::LessOrEqual, ..)));
+        // The upper bound is only tested once the lower one held.
+        match ctx.bbs[lo].instructions.last() {
+            Some(Instruction::ConditionalBranch(_, next, _)) => assert_eq!(*next as usize, hi),
+            i => panic!("lower bound test ends with {:?}", i),
+        }
+        let a = ctx.g.borrow().globals[&Global::Str("a".into())] as u32;
+        assert!(ctx.bbs[lo]
+            .instructions
+            .iter()
+            .any(|i| matches!(i, Instruction::LoadConst(_, id) if *id == a)));
+    }
+
+    #[test]
+    fn or_patterns_share_binding_registers() {
+        let ctx = compile_code("match 1 { [x, 0] | [0, x] -> x, _ -> 0 }");
+        // The first alternative that holds skips the others.
+        let (first, join) = ctx
+            .bbs
+            .iter()
+            .find_map(|bb| match bb.instructions.last() {
+                Some(Instruction::ConditionalBranch(0, join, next))
+                    if *next as usize == bb.index + 1 && *join as usize > bb.index + 1 =>
+                {
+                    Some((bb.index, *join as usize))
+                }
+                _ => None,
+            })
+            .unwrap();
+        // The last one moves its `x` to the register bound by the first one,
+        // which the arm reads.
+        let last = &ctx.bbs[join - 1].instructions;
+        assert_eq!(last.last(), Some(&Instruction::Branch(join as u16)));
+        let slot = last
+            .iter()
+            .find_map(|i| match i {
+                Instruction::Move(slot, x) if *slot != 0 && *x != 0 => Some(*slot),
+                _ => None,
+            })
+            .unwrap();
+        assert!(ctx.bbs[..first].iter().any(|bb| bb
+            .instructions
+            .iter()
+            .any(|i| matches!(i, Instruction::Move(r, _) if *r == slot))));
+        assert!(ctx.bbs[join..]
+            .iter()
+            .any(|bb| bb.instructions.contains(&Instruction::Move(0, slot))));
+    }
+
+    #[test]
+    fn destructuring_assignment_reads_before_writing() {
+        let ctx = compile_code("var a = 1\nvar b = 2\n{\n[a, b] = [b, a]\n}");
+        let (a, b) = (ctx.locals["a"] as u16, ctx.locals["b"] as u16);
+        let code = ctx
+            .bbs
+            .iter()
+            .flat_map(|bb| bb.instructions.iter())
+            .collect::<Vec<_>>();
+        let pushed = |r: u16| code.iter().position(|i| **i == Instruction::Push(r));
+        for target in [a, b].iter() {
+            let write = code
+                .iter()
+                .rposition(|i| matches!(i, Instruction::Move(r, _) if r == target))
+                .unwrap();
+            // Both variables are read into the new array before either is
+            // written, and each gets an element of that array.
+            assert!(pushed(a).unwrap() < write && pushed(b).unwrap() < write);
+            let value = match code[write] {
+                Instruction::Move(_, value) => *value,
+                _ => unreachable!(),
+            };
+            assert!(code[..write]
+                .iter()
+                .any(|i| matches!(i, Instruction::LoadByValue(r, ..) if *r == value)));
+        }
+        assert_eq!(
+            compile_error("let a = 1\n{\n[a] = [2]\n}"),
+            Msg::LetReassigned
+        );
+    }
+
+    #[test]
+    fn return_runs_finally() {
+        let ctx = compile_code("function f() { try { return 1 } finally { g() } }");
+        let g = ctx.g.borrow();
+        let finally = g.globals[&Global::Str("g".into())] as u32;
+        let f = &g.functions[0].0;
+        let (start, value) = f
+            .iter()
+            .find_map(|bb| {
+                bb.instructions.iter().find_map(|i| match i {
+                    Instruction::LoadInt(r, 1) => Some((bb.index, *r)),
+                    _ => None,
+                })
+            })
+            .unwrap();
+        // `return 1` drops the handler and runs `finally` before returning.
+        let (code, _) = run_from(f, start);
+        let pop = code.iter().position(|i| *i == Instruction::PopCatch);
+        let call = code
+            .iter()
+            .position(|i| matches!(i, Instruction::LoadStaticById(_, id) if *id == finally));
+        assert!(pop.is_some() && pop < call);
+        assert_eq!(code.last(), Some(&Instruction::Return(Some(value))));
+    }
+
+    #[test]
+    fn break_runs_defer() {
+        let ctx = compile_code("while true {\ndefer h()\nbreak\n}");
+        let deferred = ctx.g.borrow().globals[&Global::Str("h".into())] as u32;
+        let calls_h =
+            |i: &Instruction| matches!(i, Instruction::LoadStaticById(_, id) if *id == deferred);
+        let header = ctx
+            .bbs
+            .iter()
+            .position(|bb| {
+                matches!(
+                    bb.instructions.last(),
+                    Some(Instruction::ConditionalBranch(..))
+                )
+            })
+            .unwrap();
+        let start = ctx
+            .bbs
+            .iter()
+            .position(|bb| bb.instructions.iter().any(calls_h))
+            .unwrap();
+        // `break` runs the deferred call and leaves the loop without going
+        // back to its condition.
+        let (code, visited) = run_from(&ctx.bbs, start);
+        let pop = code.iter().position(|i| *i == Instruction::PopCatch);
+        assert!(pop.is_some() && pop < code.iter().position(calls_h));
+        assert!(!visited.contains(&header));
+        assert_eq!(visited.last(), Some(&(ctx.bbs.len() - 1)));
+    }
+
+    #[test]
+    fn optional_chain_short_circuits() {
+        let ctx = compile_code("a?.b.c()");
+        let (next, nil) = ctx
+            .bbs
+            .iter()
+            .find_map(|bb| match bb.instructions.last() {
+                Some(Instruction::ConditionalBranch(_, nil, next)) => {
+                    Some((*next as usize, *nil as usize))
+                }
+                _ => None,
+            })
+            .unwrap();
+        // A nil `a` skips the rest of the chain and makes it nil.
+        let rest = block_accessing(&ctx, "b");
+        assert!(next <= rest && rest < nil);
+        assert!(matches!(
+            ctx.bbs[nil].instructions[..],
+            [Instruction::LoadNull(_), Instruction::Branch(_)]
+        ));
+        assert_eq!(block_accessing(&ctx, "c"), rest);
+    }
+
+    #[test]
+    fn compound_assignment_evaluates_target_once() {
+        let ctx = compile_code("f().x += 1\na[g()] <<= 1");
+        for name in ["f", "g"].iter() {
+            let id = ctx.g.borrow().globals[&Global::Str(name.to_string())] as u32;
+            let calls = ctx
+                .bbs
+                .iter()
+                .flat_map(|bb| bb.instructions.iter())
+                .filter(|i| matches!(i, Instruction::LoadStaticById(_, g) if *g == id))
+                .count();
+            assert_eq!(calls, 1, "`{}` is called {} times", name, calls);
+        }
+        // The field and the element are read and written through the same
+        // object and index.
+        let code = ctx
+            .bbs
+            .iter()
+            .flat_map(|bb| bb.instructions.iter())
+            .collect::<Vec<_>>();
+        let read = code.iter().find_map(|i| match i {
+            Instruction::LoadByValue(_, arr, index) => Some((*arr, *index)),
+            _ => None,
+        });
+        let written = code.iter().find_map(|i| match i {
+            Instruction::StoreByValue(arr, index, _) => Some((*arr, *index)),
+            _ => None,
+        });
+        assert!(read.is_some() && read == written);
+    }
+
+    #[test]
+    fn default_and_rest_params_read_arguments() {
+        let ctx = compile_code("function f(a, b = 1, ...rest) { rest }");
+        let g = ctx.g.borrow();
+        let (code, _, _, argc, _) = &g.functions[0];
+        assert_eq!(*argc, 0);
+        let instructions = || code.iter().flat_map(|bb| bb.instructions.iter());
+        assert!(!instructions().any(|i| matches!(i, Instruction::Pop(_))));
+        // `b` is only read when a second argument was given, and the rest
+        // starts after it.
+        let index = |n: i32| {
+            instructions()
+                .find_map(|i| match i {
+                    Instruction::LoadInt(r, v) if *v == n => Some(*r),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        assert!(instructions()
+            .any(|i| matches!(i, Instruction::Binary(BinOp::Less, _, r, _) if *r == index(1))));
+        assert!(instructions().any(|i| matches!(i, Instruction::Move(_, r) if *r == index(2))));
+    }
+
+    #[test]
+    fn object_spread_copies_in_order() {
+        let ctx = compile_code("new { a: 1, ...o, b: 2 }");
+        let g = ctx.g.borrow();
+        let id = |global: Global| g.globals[&global] as u32;
+        let (a, b) = (id(Global::Str("a".into())), id(Global::Str("b".into())));
+        let assign = id(Global::Var("__assign".into()));
+        let code = ctx
+            .bbs
+            .iter()
+            .flat_map(|bb| bb.instructions.iter())
+            .collect::<Vec<_>>();
+        let at = |f: &dyn Fn(&Instruction) -> bool| code.iter().position(|i| f(i)).unwrap();
+        // Fields before the spread can be overwritten by it, the ones after
+        // it overwrite what it copied.
+        let store_a = at(&|i| matches!(i, Instruction::StoreById(_, _, id) if *id == a));
+        let spread = at(&|i| matches!(i, Instruction::LoadConst(_, id) if *id == assign));
+        let store_b = at(&|i| matches!(i, Instruction::StoreById(_, _, id) if *id == b));
+        assert!(store_a < spread && spread < store_b);
+        assert!(!g.globals.contains_key(&Global::Str("...".into())));
+        assert_eq!(
+            compile_error("var a = 1\n{a, ...o} = p"),
+            Msg::LvalueExpected
+        );
+    }
+}
@@ -0,0 +1,334 @@
+/*
+*   Copyright (c) 2020 Adel Prokurov
+*   All rights reserved.
+
+*   Licensed under the Apache License, Version 2.0 (the "License");
+*   you may not use this file except in compliance with the License.
+*   You may obtain a copy of the License at
+
+*   http://www.apache.org/licenses/LICENSE-2.0
+
+*   Unless required by applicable law or agreed to in writing, software
+*   distributed under the License is distributed on an "AS IS" BASIS,
+*   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+*   See the License for the specific language governing permissions and
+*   limitations under the License.
+*/
+
+//! Runtime helpers the compiler lowers some constructs to.
+//!
+//! Helpers are written in jlight and compiled on first use by
+//! `Context::intrinsic`, so programs that never use a construct don't pay
+//! for its helper.
+
+/// `__iter(value)` turns `value` into an iterator: an object whose `next()`
+/// returns `{done, value}`. Arrays are walked by index, objects with an
+/// `iter()` method are asked for one, anything else is assumed to already
+/// be an iterator.
+const ITER: &str = r#"
+function __iter(value) {
+    if instanceof(value, Array) {
+        return new {
+            array: value,
+            index: 0,
+            next: || {
+                let i = this.index
+                if i < this.array.length() {
+                    this.index = i + 1
+                    return new { done: false, value: this.array[i] }
+                }
+                new { done: true }
+            }
+        }
+    }
+    if isNull(value.iter) {
+        return value
+    }
+    value.iter()
+}
+"#;
+
+/// `__range(start, end, inclusive)` builds the value of `start..end`.
+const RANGE: &str = r#"
+function __range(start, end, inclusive) {
+    new {
+        start,
+        end,
+        inclusive,
+        contains: |x| {
+            if this.inclusive {
+                return x >= this.start && x <= this.end
+            }
+            x >= this.start && x < this.end
+        },
+        iter: || new {
+            current: this.start,
+            end: this.end,
+            inclusive: this.inclusive,
+            next: || {
+                let value = this.current
+                let more = if this.inclusive {
+                    value <= this.end
+                } else {
+                    value < this.end
+                }
+                if more {
+                    this.current = value + 1
+                    return new { done: false, value }
+                }
+                new { done: true }
+            }
+        }
+    }
+}
+"#;
+
+/// `__assign(target, source)` copies the fields of `source` into `target`,
+/// for `new { ...source }`. The names of the fields come from
+/// `Object.keys(source)` as an array.
+const ASSIGN: &str = r#"
+function __assign(target, source) {
+    let keys = Object.keys(source)
+    var i = 0
+    while i < keys.length() {
+        let key = keys[i]
+        target[key] = source[key]
+        i = i + 1
+    }
+    target
+}
+"#;
+
+/// `__async(body)` runs `body()` in a new process for an `async function`
+/// call and returns the task `await` waits on. The process keeps the result,
+/// or what `body` threw, until the awaiting process asks for it.
+const ASYNC: &str = r#"
+function __async(body) {
+    new {
+        process: Process.spawn(|| {
+            let reply = new { awaited: true, ok: true, value: nil }
+            try {
+                reply.value = body()
+            } catch e {
+                reply.ok = false
+                reply.value = e
+            }
+            Process.send(Process.recv(), reply)
+        }),
+        settled: false
+    }
+}
+"#;
+
+/// `__await(task)` suspends the current process until the task of an
+/// `async function` call has finished. Returns its result or rethrows what
+/// it threw. Other messages received meanwhile are sent back to the current
+/// process, so `Process.recv()` still sees them afterwards.
+const AWAIT: &str = r#"
+function __await(task) {
+    if !task.settled {
+        let me = Process.current()
+        Process.send(task.process, me)
+        let others = new Array()
+        var reply = Process.recv()
+        while isNull(reply.awaited) {
+            others.push(reply)
+            reply = Process.recv()
+        }
+        for message in others {
+            Process.send(me, message)
+        }
+        task.settled = true
+        task.ok = reply.ok
+        task.value = reply.value
+    }
+    if task.ok {
+        return task.value
+    }
+    throw task.value
+}
+"#;
+
+/// `__timer(ms)` starts the timer of `receive ... after ms` and returns it:
+/// an object of its own, sent as `{timeout: <timer>}` to the receiving
+/// process after `ms` milliseconds unless the receive cancelled it first.
+const TIMER: &str = r#"
+function __timer(ms) {
+    let receiver = Process.current()
+    let timer = new { cancelled: false }
+    Process.spawn(|| {
+        Process.sleep(ms)
+        if !timer.cancelled {
+            Process.send(receiver, new { timeout: timer })
+        }
+    })
+    timer
+}
+"#;
+
+/// `__is_timeout(message, timer)` tells whether `message` was sent by
+/// `timer`.
+const IS_TIMEOUT: &str = r#"
+function __is_timeout(message, timer) {
+    if isNull(message) || isNull(message.timeout) {
+        return false
+    }
+    message.timeout == timer
+}
+"#;
+
+/// `__is_stale(message)` tells whether `message` was sent by a timer that
+/// was cancelled after it had already fired. Every `receive` drops these.
+const IS_STALE: &str = r#"
+function __is_stale(message) {
+    if isNull(message) || isNull(message.timeout) {
+        return false
+    }
+    message.timeout.cancelled == true
+}
+"#;
+
+/// `__requeue(messages)` sends messages set aside by `receive` back to the
+/// current process.
+const REQUEUE: &str = r#"
+function __requeue(messages) {
+    let me = Process.current()
+    for message in messages {
+        Process.send(me, message)
+    }
+}
+"#;
+
+/// `__par_map(array, f, workers)` sends the elements of `array` round-robin
+/// to `workers` processes applying `f` and collects the results in order.
+/// Once every element came back, the error `f` threw for the element with
+/// the lowest index is rethrown, whatever order the workers finished in.
+const PAR_MAP: &str = r#"
+function __par_map(array, f, workers) {
+    if workers < 1 {
+        throw "par_map needs at least one worker"
+    }
+    let me = Process.current()
+    let count = array.length()
+    let pool = new Array()
+    for _ in 0..workers {
+        if pool.length() < count {
+            pool.push(Process.spawn(|| {
+                var running = true
+                while running {
+                    receive {
+                        [:job, i, x] -> try {
+                            Process.send(me, [:par_map, i, true, f(x)])
+                        } catch e {
+                            Process.send(me, [:par_map, i, false, e])
+                        },
+                        :stop -> running = false
+                    }
+                }
+            }))
+        }
+    }
+    let results = new Array()
+    for i in 0..count {
+        results.push(nil)
+        Process.send(pool[i % pool.length()], [:job, i, array[i]])
+    }
+    var failed = count
+    var failure = nil
+    for _ in 0..count {
+        receive {
+            [:par_map, i, true, value] -> results[i] = value,
+            [:par_map, i, false, error] -> if i < failed {
+                failed = i
+                failure = error
+            }
+        }
+    }
+    for worker in pool {
+        Process.send(worker, :stop)
+    }
+    if failed < count {
+        throw failure
+    }
+    results
+}
+"#;
+
+/// `__atom(atom, name)` returns `atom` if another module already created
+/// it, or a new atom named `name` otherwise. Atoms are objects of their own
+/// so that no string equals them.
+const ATOM: &str = r#"
+function __atom(atom, name) {
+    if atom {
+        return atom
+    }
+    new { name }
+}
+"#;
+
+pub fn source(name: &str) -> Option<&'static str> {
+    match name {
+        "__iter" => Some(ITER),
+        "__range" => Some(RANGE),
+        "__assign" => Some(ASSIGN),
+        "__async" => Some(ASYNC),
+        "__await" => Some(AWAIT),
+        "__timer" => Some(TIMER),
+        "__is_timeout" => Some(IS_TIMEOUT),
+        "__is_stale" => Some(IS_STALE),
+        "__requeue" => Some(REQUEUE),
+        "__par_map" => Some(PAR_MAP),
+        "__atom" => Some(ATOM),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::Context;
+    use crate::parser::Parser;
+    use crate::reader::Reader;
+
+    #[test]
+    fn intrinsics_parse() {
+        for name in [
+            "__iter",
+            "__range",
+            "__assign",
+            "__async",
+            "__await",
+            "__timer",
+            "__is_timeout",
+            "__is_stale",
+            "__requeue",
+            "__par_map",
+            "__atom",
+        ].iter() {
+            let mut ast = vec![];
+            Parser::new(Reader::from_string(source(name).unwrap()), &mut ast)
+                .parse()
+                .unwrap_or_else(|e| panic!("{}: {}", name, e));
+        }
+    }
+
+    #[test]
+    fn intrinsics_compile() {
+        let mut ctx = Context::new();
+        for name in [
+            "__iter",
+            "__range",
+            "__assign",
+            "__async",
+            "__await",
+            "__timer",
+            "__is_timeout",
+            "__is_stale",
+            "__requeue",
+            "__par_map",
+            "__atom",
+        ].iter() {
+            ctx.intrinsic(name);
+        }
+    }
+}
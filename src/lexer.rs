@@ -19,10 +19,13 @@ use crate::msg::{Msg, MsgWithPos};
 use crate::reader::Reader;
 use crate::token::*;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 pub struct Lexer {
     reader: Reader,
     keywords: HashMap<&'static str, TokenKind>,
+    /// Tokens looked ahead at or pushed by macro expansions, read before the
+    /// rest of the input.
+    pending: VecDeque<Token>,
 }
 use hmap::hmap;
 
@@ -53,6 +56,19 @@ impl Lexer {
             "nil" => TokenKind::Nil,
             "type" => TokenKind::Type,
             "throw" => TokenKind::Throw,
+            "try" => TokenKind::Try,
+            "catch" => TokenKind::Catch,
+            "finally" => TokenKind::Finally,
+            "break" => TokenKind::Break,
+            "continue" => TokenKind::Continue,
+            "defer" => TokenKind::Defer,
+            "yield" => TokenKind::Yield,
+            "async" => TokenKind::Async,
+            "await" => TokenKind::Await,
+            "receive" => TokenKind::Receive,
+            "actor" => TokenKind::Actor,
+            "parallel" => TokenKind::Parallel,
+            "macro" => TokenKind::Macro,
             "do" => TokenKind::Do,
             "import" => TokenKind::Import,
             "class" => TokenKind::Class,
@@ -63,6 +79,7 @@ impl Lexer {
         Lexer {
             reader: reader,
             keywords: keywords,
+            pending: VecDeque::new(),
         }
     }
 
@@ -90,7 +107,31 @@ impl Lexer {
         Ok(())
     }
 
+    /// Makes `tokens` the next tokens read.
+    pub fn push_tokens(&mut self, tokens: Vec<Token>) {
+        for tok in tokens.into_iter().rev() {
+            self.pending.push_front(tok);
+        }
+    }
+
     pub fn read_token(&mut self) -> Result<Token, MsgWithPos> {
+        match self.pending.pop_front() {
+            Some(tok) => Ok(tok),
+            None => self.scan_token(),
+        }
+    }
+
+    /// Looks ahead without consuming anything: `peek_token(0)` is the token
+    /// the next `read_token` returns, `peek_token(1)` the one after it.
+    pub fn peek_token(&mut self, n: usize) -> Result<&Token, MsgWithPos> {
+        while self.pending.len() <= n {
+            let tok = self.scan_token()?;
+            self.pending.push_back(tok);
+        }
+        Ok(&self.pending[n])
+    }
+
+    fn scan_token(&mut self) -> Result<Token, MsgWithPos> {
         loop {
             self.skip_white();
 
@@ -224,6 +265,17 @@ impl Lexer {
         }
     }
 
+    /// Returns `compound` and consumes the `=` if one follows the operator,
+    /// as in `+=`.
+    fn with_eq(&mut self, op: TokenKind, compound: TokenKind) -> TokenKind {
+        if self.cur() == Some('=') {
+            self.read_char();
+            compound
+        } else {
+            op
+        }
+    }
+
     fn read_operator(&mut self) -> Result<Token, MsgWithPos> {
         let mut tok = self.build_token(TokenKind::End);
         let ch = self.cur().unwrap();
@@ -232,19 +284,19 @@ impl Lexer {
         let nch = self.cur().unwrap_or('x');
 
         tok.kind = match ch {
-            '+' => TokenKind::Add,
+            '+' => self.with_eq(TokenKind::Add, TokenKind::AddEq),
             '-' => {
                 if nch == '>' {
                     self.read_char();
                     TokenKind::Arrow
                 } else {
-                    TokenKind::Sub
+                    self.with_eq(TokenKind::Sub, TokenKind::SubEq)
                 }
             }
 
-            '*' => TokenKind::Mul,
-            '/' => TokenKind::Div,
-            '%' => TokenKind::Mod,
+            '*' => self.with_eq(TokenKind::Mul, TokenKind::MulEq),
+            '/' => self.with_eq(TokenKind::Div, TokenKind::DivEq),
+            '%' => self.with_eq(TokenKind::Mod, TokenKind::ModEq),
 
             '(' => TokenKind::LParen,
             ')' => TokenKind::RParen,
@@ -257,8 +309,11 @@ impl Lexer {
                 if nch == '|' {
                     self.read_char();
                     TokenKind::Or
+                } else if nch == '>' {
+                    self.read_char();
+                    TokenKind::Pipe
                 } else {
-                    TokenKind::BitOr
+                    self.with_eq(TokenKind::BitOr, TokenKind::BitOrEq)
                 }
             }
 
@@ -267,18 +322,28 @@ impl Lexer {
                     self.read_char();
                     TokenKind::And
                 } else {
-                    TokenKind::BitAnd
+                    self.with_eq(TokenKind::BitAnd, TokenKind::BitAndEq)
                 }
             }
 
-            '^' => TokenKind::Caret,
+            '^' => self.with_eq(TokenKind::Caret, TokenKind::CaretEq),
             '~' => TokenKind::Tilde,
+            '@' => TokenKind::At,
             ',' => TokenKind::Comma,
             ';' => TokenKind::Semicolon,
             ':' => {
                 if nch == ':' {
                     self.read_char();
                     TokenKind::Sep
+                } else if is_identifier_start(Some(nch)) {
+                    // `x:y` is `x` followed by the atom `:y` too, the parser
+                    // reads it as a label where one is expected.
+                    let mut value = String::new();
+                    while is_identifier(self.cur()) {
+                        value.push(self.cur().unwrap());
+                        self.read_char();
+                    }
+                    TokenKind::Atom(value)
                 } else {
                     TokenKind::Colon
                 }
@@ -286,7 +351,15 @@ impl Lexer {
             '.' => {
                 if nch == '.' {
                     self.read_char();
-                    TokenKind::DotDot
+                    if self.cur() == Some('=') {
+                        self.read_char();
+                        TokenKind::DotDotEq
+                    } else if self.cur() == Some('.') {
+                        self.read_char();
+                        TokenKind::DotDotDot
+                    } else {
+                        TokenKind::DotDot
+                    }
                 } else {
                     TokenKind::Dot
                 }
@@ -311,7 +384,7 @@ impl Lexer {
 
                 '<' => {
                     self.read_char();
-                    TokenKind::LtLt
+                    self.with_eq(TokenKind::LtLt, TokenKind::LtLtEq)
                 }
 
                 _ => TokenKind::Lt,
@@ -325,13 +398,36 @@ impl Lexer {
 
                 '>' => {
                     self.read_char();
-
-                    TokenKind::GtGt
+                    if self.cur() == Some('>') {
+                        self.read_char();
+                        self.with_eq(TokenKind::GtGtGt, TokenKind::GtGtGtEq)
+                    } else {
+                        self.with_eq(TokenKind::GtGt, TokenKind::GtGtEq)
+                    }
                 }
 
                 _ => TokenKind::Gt,
             },
 
+            '?' => match nch {
+                '.' => {
+                    self.read_char();
+                    TokenKind::QuestionDot
+                }
+
+                '[' => {
+                    self.read_char();
+                    TokenKind::QuestionBracket
+                }
+
+                '?' => {
+                    self.read_char();
+                    TokenKind::QuestionQuestion
+                }
+
+                _ => return Err(MsgWithPos::new(tok.position, Msg::UnknownChar(ch))),
+            },
+
             '!' => {
                 if nch == '=' {
                     self.read_char();
@@ -476,7 +572,7 @@ fn is_char_quote(ch: Option<char>) -> bool {
 }
 
 fn is_operator(ch: Option<char>) -> bool {
-    ch.map(|ch| "^+-*/%&|,=!~;:.()[]{}<>".contains(ch))
+    ch.map(|ch| "^+-*/%&|,=!~@?;:.()[]{}<>".contains(ch))
         .unwrap_or(false)
 }
 
@@ -490,3 +586,148 @@ fn is_identifier_start(ch: Option<char>) -> bool {
 fn is_identifier(ch: Option<char>) -> bool {
     is_identifier_start(ch) || is_digit(ch)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(code: &str) -> Vec<TokenKind> {
+        let mut lexer = Lexer::from_str(code);
+        let mut tokens = vec![];
+        loop {
+            let tok = lexer.read_token().unwrap();
+            if tok.is_eof() {
+                break;
+            }
+            tokens.push(tok.kind);
+        }
+        tokens
+    }
+
+    #[test]
+    fn lex_atoms() {
+        assert_eq!(
+            tokens("[:chunk, x] :: :ok"),
+            vec![
+                TokenKind::LBracket,
+                TokenKind::Atom("chunk".into()),
+                TokenKind::Comma,
+                TokenKind::Identifier("x".into()),
+                TokenKind::RBracket,
+                TokenKind::Sep,
+                TokenKind::Atom("ok".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_atoms_after_operand() {
+        let atom = |before: TokenKind| vec![before, TokenKind::Atom("y".into())];
+        assert_eq!(tokens("x :y"), atom(TokenKind::Identifier("x".into())));
+        assert_eq!(tokens(")\n:y"), atom(TokenKind::RParen));
+        assert_eq!(tokens("]:y"), atom(TokenKind::RBracket));
+        assert_eq!(
+            tokens("x: y"),
+            vec![
+                TokenKind::Identifier("x".into()),
+                TokenKind::Colon,
+                TokenKind::Identifier("y".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_ranges() {
+        assert_eq!(
+            tokens("0..9 'a'..='z'"),
+            vec![
+                TokenKind::LitInt("0".into(), IntBase::Dec, IntSuffix::Int),
+                TokenKind::DotDot,
+                TokenKind::LitInt("9".into(), IntBase::Dec, IntSuffix::Int),
+                TokenKind::LitChar('a'),
+                TokenKind::DotDotEq,
+                TokenKind::LitChar('z'),
+            ]
+        );
+        assert_eq!(
+            tokens("...xs"),
+            vec![TokenKind::DotDotDot, TokenKind::Identifier("xs".into())]
+        );
+    }
+
+    #[test]
+    fn lex_optional_operators() {
+        assert_eq!(
+            tokens("a?.b?[0] ?? c"),
+            vec![
+                TokenKind::Identifier("a".into()),
+                TokenKind::QuestionDot,
+                TokenKind::Identifier("b".into()),
+                TokenKind::QuestionBracket,
+                TokenKind::LitInt("0".into(), IntBase::Dec, IntSuffix::Int),
+                TokenKind::RBracket,
+                TokenKind::QuestionQuestion,
+                TokenKind::Identifier("c".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_pipe() {
+        assert_eq!(
+            tokens("x |> f | g"),
+            vec![
+                TokenKind::Identifier("x".into()),
+                TokenKind::Pipe,
+                TokenKind::Identifier("f".into()),
+                TokenKind::BitOr,
+                TokenKind::Identifier("g".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_compound_assignment() {
+        assert_eq!(
+            tokens("+= -= *= /= %= <<= >>= &= |= ^= << -> |>"),
+            vec![
+                TokenKind::AddEq,
+                TokenKind::SubEq,
+                TokenKind::MulEq,
+                TokenKind::DivEq,
+                TokenKind::ModEq,
+                TokenKind::LtLtEq,
+                TokenKind::GtGtEq,
+                TokenKind::BitAndEq,
+                TokenKind::BitOrEq,
+                TokenKind::CaretEq,
+                TokenKind::LtLt,
+                TokenKind::Arrow,
+                TokenKind::Pipe,
+            ]
+        );
+        assert_eq!(
+            tokens(">> >>> >>>= ~"),
+            vec![
+                TokenKind::GtGt,
+                TokenKind::GtGtGt,
+                TokenKind::GtGtGtEq,
+                TokenKind::Tilde,
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_field_label_as_atom() {
+        // The parser turns the atom back into a label.
+        assert_eq!(
+            tokens("{x:y}"),
+            vec![
+                TokenKind::LBrace,
+                TokenKind::Identifier("x".into()),
+                TokenKind::Atom("y".into()),
+                TokenKind::RBrace,
+            ]
+        );
+    }
+}
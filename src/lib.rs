@@ -17,6 +17,7 @@
 
 pub mod ast;
 pub mod codegen;
+pub mod intrinsics;
 pub mod lexer;
 pub mod match_;
 pub mod msg;
@@ -32,6 +32,14 @@ pub enum Msg {
     UnknownMethod(String, String, Vec<String>),
     UnknownStaticMethod(String, String, Vec<String>),
     UnknownCtor(String, Vec<String>),
+    UnknownKeywordArg(String, String),
+    MissingKeywordArg(String, String),
+    NoKeywordArgs(String),
+    MacroArgs(String, usize, usize),
+    MacroRecursion(String),
+    /// An error in the expansion of a macro, reported at the call site with
+    /// the position of the error inside the expansion.
+    InMacro(String, Position, Box<Msg>),
     MethodExists(String, String, Position),
     IncompatibleWithNil(String),
     IdentifierExists(String),
@@ -84,6 +92,7 @@ pub enum Msg {
     NumberOverflow(String),
     ExpectedClass(String),
     ExpectedFactor(String),
+    ExpectedPattern(String),
     ExpectedToken(String, String),
     ExpectedTopLevelElement(String),
     ExpectedTrait(String),
@@ -92,6 +101,7 @@ pub enum Msg {
     MisplacedElse,
     IoError,
     ExpectedClassElement(String),
+    ExpectedActorElement(String),
     RedundantModifier(String),
     MisplacedModifier(String),
     InvalidEscapeSequence(char),
@@ -154,6 +164,24 @@ impl Msg {
                 let args = args.join(", ");
                 format!("no static method `{}::{}({})`.", cls, name, args)
             }
+            UnknownKeywordArg(ref fct, ref name) => {
+                format!("function `{}` has no keyword argument `{}`.", fct, name)
+            }
+            MissingKeywordArg(ref fct, ref name) => {
+                format!("call to `{}` is missing keyword argument `{}`.", fct, name)
+            }
+            NoKeywordArgs(ref fct) => format!("function `{}` takes no keyword arguments.", fct),
+            MacroArgs(ref name, expected, given) => format!(
+                "macro `{}` takes {} arguments but {} were given.",
+                name, expected, given
+            ),
+            MacroRecursion(ref name) => format!("expansion of macro `{}` nested too deeply.", name),
+            InMacro(ref name, pos, ref msg) => format!(
+                "in expansion of macro `{}`: error at {}: {}",
+                name,
+                pos,
+                msg.message()
+            ),
             UnknownCtor(ref name, ref args) => {
                 let args = args.join(", ");
                 format!("no ctor with definition `{}({})`.", name, args)
@@ -258,6 +286,7 @@ impl Msg {
             NumberOverflow(ref ty) => format!("number does not fit into type {}.", ty),
             ExpectedClass(ref cls) => format!("expected class name but got {}.", cls),
             ExpectedFactor(ref got) => format!("factor expected but got {}.", got),
+            ExpectedPattern(ref got) => format!("pattern expected but got {}.", got),
             ExpectedTrait(ref trt) => format!("expected trait name but got {}.", trt),
             ExpectedType(ref got) => format!("type expected but got {}.", got),
             ExpectedIdentifier(ref tok) => format!("identifier expected but got {}.", tok),
@@ -268,6 +297,9 @@ impl Msg {
             ExpectedClassElement(ref token) => {
                 format!("field or method expected but got {}.", token)
             }
+            ExpectedActorElement(ref token) => {
+                format!("`state` or `on` expected but got {}.", token)
+            }
             RedundantModifier(ref token) => format!("redundant modifier {}.", token),
             UnknownChar(ch) => format!("unknown character {} (codepoint {}).", ch, ch as usize),
             UnclosedComment => "unclosed comment.".into(),
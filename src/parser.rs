@@ -20,14 +20,39 @@ use crate::lexer::*;
 use crate::msg::*;
 use crate::reader::Reader;
 use crate::token::*;
+use std::collections::{HashMap, HashSet};
 use std::mem;
 
 pub struct Parser<'a> {
     lexer: Lexer,
     token: Token,
+    /// Position of the previously consumed token.
+    last_pos: Position,
     ast: &'a mut Vec<Box<Expr>>,
+    macros: HashMap<String, MacroDef>,
+    /// Number of macro calls expanded so far, to name introduced bindings.
+    expansions: usize,
+    /// Macro calls being expanded around the current token.
+    expanding: usize,
 }
 
+/// `macro name(params) { template }`
+#[derive(Clone)]
+struct MacroDef {
+    params: Vec<String>,
+    template: Vec<Token>,
+    /// Names the template binds, see `Parser::template_bindings`. They are
+    /// renamed in every expansion so arguments can't refer to them.
+    introduced: HashSet<String>,
+}
+
+/// Macro calls nested deeper than this are assumed to recurse forever.
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// Precedence of `|` in `parse_binary`. The default value of a lambda
+/// parameter stops before the `|` closing the parameter list.
+const BIT_OR_PRECEDENCE: u32 = 5;
+
 macro_rules! expr {
     ($e:expr,$pos:expr) => {
         Box::new(Expr {
@@ -44,7 +69,11 @@ impl<'a> Parser<'a> {
         Self {
             lexer: Lexer::new(reader),
             token: Token::new(TokenKind::End, Position::new(1, 1)),
+            last_pos: Position::new(1, 1),
             ast,
+            macros: HashMap::new(),
+            expansions: 0,
+            expanding: 0,
         }
     }
 
@@ -76,6 +105,9 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_top_level(&mut self) -> Result<(), MsgWithPos> {
+        if self.token.is(TokenKind::Macro) {
+            return self.parse_macro();
+        }
         let expr = self.parse_expression()?;
 
         self.ast.push(expr);
@@ -84,6 +116,10 @@ impl<'a> Parser<'a> {
 
     fn parse_function(&mut self) -> EResult {
         let pos = self.expect_token(TokenKind::Fun)?.position;
+        let generator = self.token.is(TokenKind::Mul);
+        if generator {
+            self.advance_token()?;
+        }
         let name = if let TokenKind::Identifier(_) = &self.token.kind {
             Some(self.expect_identifier()?)
         } else {
@@ -103,32 +139,64 @@ impl<'a> Parser<'a> {
             tmp
         };
         self.expect_token(TokenKind::RParen)?;*/
-        let params = self.parse_comma_list(TokenKind::RParen, |parser| parser.parse_arg())?;
+        let params = self.parse_comma_list(TokenKind::RParen, |parser| parser.parse_arg(false))?;
+        check_params(pos, &params)?;
         let block = self.parse_block()?;
+        if generator {
+            return Ok(expr!(ExprKind::Generator(name, params, block), pos));
+        }
         Ok(expr!(ExprKind::Function(name, params, block), pos))
     }
 
-    fn parse_arg(&mut self) -> Result<Arg, MsgWithPos> {
+    fn parse_async(&mut self) -> EResult {
+        let pos = self.expect_token(TokenKind::Async)?.position;
+        let function = self.parse_function()?;
+        match function.expr {
+            ExprKind::Function(name, params, block) => {
+                Ok(expr!(ExprKind::AsyncFunction(name, params, block), pos))
+            }
+            _ => Err(MsgWithPos::new(
+                pos,
+                Msg::Custom("generators can't be `async`".to_owned()),
+            )),
+        }
+    }
+
+    /// Parses a parameter. In a lambda a default value stops before `|`.
+    fn parse_arg(&mut self, lambda: bool) -> Result<Arg, MsgWithPos> {
         let pos = self.token.position;
         let tok = self.token.kind.name();
         match self.token.kind {
-            TokenKind::Var => {
+            TokenKind::Var | TokenKind::Identifier { .. } => {
+                let mutable = self.token.is(TokenKind::Var);
+                if mutable {
+                    self.advance_token()?;
+                }
+                let name = self.expect_identifier()?;
+                if !self.token.is(TokenKind::Eq) {
+                    return Ok(Arg::Ident(mutable, name));
+                }
                 self.advance_token()?;
-                Ok(Arg::Ident(true, self.expect_identifier()?))
-            }
-            TokenKind::Identifier { .. } => Ok(Arg::Ident(false, self.expect_identifier()?)),
-            TokenKind::LBrace => {
-                self.expect_token(TokenKind::LBrace)?;
-                let list: Vec<String> =
-                    self.parse_comma_list(TokenKind::RBrace, |parser| parser.expect_identifier())?;
-                Ok(Arg::Record(list))
+                let default = if lambda {
+                    self.parse_binary(BIT_OR_PRECEDENCE)?
+                } else {
+                    self.parse_expression()?
+                };
+                Ok(Arg::Default(mutable, name, default))
             }
-            TokenKind::LBracket => {
-                self.expect_token(TokenKind::LBracket)?;
-                let list: Vec<String> = self
-                    .parse_comma_list(TokenKind::RBracket, |parser| parser.expect_identifier())?;
-                Ok(Arg::Array(list))
+            TokenKind::DotDotDot => {
+                self.advance_token()?;
+                Ok(Arg::Rest(self.expect_identifier()?))
             }
+            TokenKind::LBrace => match self.precord()?.decl {
+                PatternDecl::Record(fields) => Ok(Arg::Record(fields)),
+                _ => unreachable!(),
+            },
+            TokenKind::LBracket => match self.parray()?.decl {
+                PatternDecl::Array(items) => Ok(Arg::Array(items)),
+                PatternDecl::EmptyList => Ok(Arg::Array(vec![])),
+                _ => unreachable!(),
+            },
             _ => Err(MsgWithPos::new(
                 pos,
                 Msg::Custom(format!("unexpected token '{}' in argument position.", tok,)),
@@ -160,18 +228,207 @@ impl<'a> Parser<'a> {
                 Ok(expr!(ExprKind::New(calling), pos))
             }*/
             TokenKind::Fun => self.parse_function(),
+            TokenKind::Async => self.parse_async(),
             TokenKind::Class => self.parse_class(),
+            TokenKind::Actor => self.parse_actor(),
+            TokenKind::Parallel => self.parse_parallel(),
             TokenKind::Match => self.parse_match(),
+            TokenKind::Receive => self.parse_receive(),
             TokenKind::Let | TokenKind::Var => self.parse_let(),
-            TokenKind::LBrace => self.parse_block(),
+            TokenKind::LBrace => {
+                if self.is_record_assignment()? {
+                    self.parse_record_assignment()
+                } else {
+                    self.parse_block()
+                }
+            }
             TokenKind::If => self.parse_if(),
             TokenKind::While => self.parse_while(),
+            TokenKind::For => self.parse_for(),
             TokenKind::Return => self.parse_return(),
             TokenKind::Throw => self.parse_throw(),
+            TokenKind::Try => self.parse_try(),
+            TokenKind::Break => {
+                let pos = self.advance_token()?.position;
+                Ok(expr!(ExprKind::Break, pos))
+            }
+            TokenKind::Continue => {
+                let pos = self.advance_token()?.position;
+                Ok(expr!(ExprKind::Continue, pos))
+            }
+            TokenKind::Defer => {
+                let pos = self.advance_token()?.position;
+                Ok(expr!(ExprKind::Defer(self.parse_expression()?), pos))
+            }
+            TokenKind::Yield => {
+                let pos = self.advance_token()?.position;
+                if self.token.is(TokenKind::Mul) {
+                    self.advance_token()?;
+                    return Ok(expr!(ExprKind::YieldFrom(self.parse_expression()?), pos));
+                }
+                Ok(expr!(ExprKind::Yield(self.parse_expression()?), pos))
+            }
             _ => self.parse_binary(0),
         }
     }
 
+    /// Looks at most three tokens past the `{` at the current token:
+    /// `{x, y} = point`, `{x: p} = point` and `{x} = point` are destructuring
+    /// assignments, anything else is a block.
+    fn is_record_assignment(&mut self) -> Result<bool, MsgWithPos> {
+        let line = match self.lexer.peek_token(0)? {
+            Token {
+                kind: TokenKind::Identifier(_),
+                position,
+            } => position.line,
+            _ => return Ok(false),
+        };
+        let next = self.lexer.peek_token(1)?;
+        if is_label(line, next) {
+            return Ok(true);
+        }
+        Ok(match next.kind {
+            TokenKind::Comma => true,
+            TokenKind::RBrace => self.lexer.peek_token(2)?.is(TokenKind::Eq),
+            _ => false,
+        })
+    }
+
+    fn parse_record_assignment(&mut self) -> EResult {
+        let pos = self.expect_token(TokenKind::LBrace)?.position;
+        let fields = self.parse_object_fields()?;
+        let target = expr!(ExprKind::NewObject(fields), pos);
+        let tok = self.expect_token(TokenKind::Eq)?;
+        let value = self.parse_expression()?;
+        Ok(expr!(ExprKind::Assign(target, value), tok.position))
+    }
+
+    fn parse_object_fields(&mut self) -> Result<Vec<ObjectField>, MsgWithPos> {
+        self.parse_comma_list(TokenKind::RBrace, |p| {
+            if p.token.is(TokenKind::DotDotDot) {
+                p.advance_token()?;
+                return Ok(ObjectField::Spread(p.parse_expression()?));
+            }
+            let pos = p.token.position;
+            let name = p.expect_identifier()?;
+            if is_label(pos.line, &p.token) {
+                p.expect_label()?;
+                return Ok(ObjectField::Field(name, Some(p.parse_expression()?)));
+            }
+            match renamed_field(&name) {
+                Some(field) => Ok(ObjectField::Field(
+                    field,
+                    Some(expr!(ExprKind::Ident(name), pos)),
+                )),
+                None => Ok(ObjectField::Field(name, None)),
+            }
+        })
+    }
+
+    /// Parses a call of `callee` up to `)`. Trailing `name: value`
+    /// arguments are collected into one `ExprKind::KeywordArgs` argument.
+    fn parse_call(&mut self, callee: Box<Expr>) -> EResult {
+        let mut keywords: Vec<(String, Box<Expr>)> = vec![];
+        let mut keywords_pos = None;
+        let args = self.parse_comma_list(TokenKind::RParen, |p| {
+            let pos = p.token.position;
+            if p.is_keyword_arg()? {
+                let name = p.expect_identifier()?;
+                p.expect_label()?;
+                let value = p.parse_expression()?;
+                if keywords.iter().any(|(n, _)| *n == name) {
+                    return Err(MsgWithPos::new(
+                        pos,
+                        Msg::Custom(format!("keyword argument '{}' given twice", name)),
+                    ));
+                }
+                keywords_pos.get_or_insert(pos);
+                keywords.push((name, value));
+                return Ok(None);
+            }
+            if !keywords.is_empty() {
+                return Err(MsgWithPos::new(
+                    pos,
+                    Msg::Custom("positional argument after keyword arguments".to_owned()),
+                ));
+            }
+            Ok(Some(p.parse_element()?))
+        })?;
+        let mut args: Vec<Box<Expr>> = args.into_iter().flatten().collect();
+        if let Some(pos) = keywords_pos {
+            args.push(expr!(ExprKind::KeywordArgs(keywords), pos));
+        }
+        Ok(expr!(ExprKind::Call(callee, args), callee.pos))
+    }
+
+    /// Consumes the `:` of `name: value`, see `is_label`.
+    fn expect_label(&mut self) -> Result<(), MsgWithPos> {
+        if let TokenKind::Atom(name) = &self.token.kind {
+            let pos = self.token.position;
+            self.token = Token::new(
+                TokenKind::Identifier(name.clone()),
+                Position::new(pos.line, pos.column + 1),
+            );
+            return Ok(());
+        }
+        self.expect_token(TokenKind::Colon).map(|_| ())
+    }
+
+    /// Looks one token ahead for the `:` of a `name: value` argument.
+    fn is_keyword_arg(&mut self) -> Result<bool, MsgWithPos> {
+        if let TokenKind::Identifier(_) = self.token.kind {
+            let line = self.token.position.line;
+            Ok(is_label(line, self.lexer.peek_token(0)?))
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Parses an array literal or a comprehension.
+    fn parse_array(&mut self) -> EResult {
+        let pos = self.expect_token(TokenKind::LBracket)?.position;
+        if self.token.is(TokenKind::RBracket) {
+            self.advance_token()?;
+            return Ok(expr!(ExprKind::Array(vec![]), pos));
+        }
+        let first = self.parse_element()?;
+        if self.token.is(TokenKind::For) && !matches!(first.expr, ExprKind::Spread(_)) {
+            let mut clauses = vec![];
+            while self.token.is(TokenKind::For) {
+                self.advance_token()?;
+                let pat = self.parse_pattern()?;
+                self.expect_token(TokenKind::In)?;
+                let iter = self.parse_expression()?;
+                let mut conds = vec![];
+                while self.token.is(TokenKind::If) {
+                    self.advance_token()?;
+                    conds.push(self.parse_expression()?);
+                }
+                clauses.push((pat, iter, conds));
+            }
+            self.expect_token(TokenKind::RBracket)?;
+            return Ok(expr!(ExprKind::Comprehension(first, clauses), pos));
+        }
+        let mut items = vec![first];
+        if self.token.is(TokenKind::Comma) {
+            self.advance_token()?;
+            items.extend(self.parse_comma_list(TokenKind::RBracket, |p| p.parse_element())?);
+        } else {
+            self.expect_token(TokenKind::RBracket)?;
+        }
+        Ok(expr!(ExprKind::Array(items), pos))
+    }
+
+    /// Parses an element of an argument list or array literal, which may be
+    /// `...value`.
+    fn parse_element(&mut self) -> EResult {
+        if self.token.is(TokenKind::DotDotDot) {
+            let pos = self.advance_token()?.position;
+            return Ok(expr!(ExprKind::Spread(self.parse_expression()?), pos));
+        }
+        self.parse_expression()
+    }
+
     fn parse_self(&mut self) -> EResult {
         let pos = self.expect_token(TokenKind::This)?.position;
         Ok(expr!(ExprKind::This, pos))
@@ -183,16 +440,72 @@ impl<'a> Parser<'a> {
         Ok(expr!(ExprKind::Throw(self.parse_expression()?), pos))
     }
 
+    fn parse_try(&mut self) -> EResult {
+        let pos = self.expect_token(TokenKind::Try)?.position;
+        let body = self.parse_block()?;
+        let mut catches = vec![];
+        while self.token.is(TokenKind::Catch) {
+            self.advance_token()?;
+            let pat = self.parse_pattern()?;
+            let guard = if self.token.is(TokenKind::When) || self.token.is(TokenKind::Or) {
+                self.advance_token()?;
+                Some(self.parse_expression()?)
+            } else {
+                None
+            };
+            let block = self.parse_block()?;
+            catches.push((pat, guard, block));
+        }
+        let finally = if self.token.is(TokenKind::Finally) {
+            self.advance_token()?;
+            Some(self.parse_block()?)
+        } else {
+            None
+        };
+        if catches.is_empty() && finally.is_none() {
+            return Err(MsgWithPos::new(pos, Msg::CatchOrFinallyExpected));
+        }
+        Ok(expr!(ExprKind::Try(body, catches, finally), pos))
+    }
+
     fn parse_while(&mut self) -> EResult {
         let pos = self.expect_token(TokenKind::While)?.position;
+        if self.token.is(TokenKind::Let) {
+            let (pat, value) = self.parse_let_condition()?;
+            let block = self.parse_block()?;
+            return Ok(expr!(ExprKind::WhileLet(pat, value, block), pos));
+        }
         let cond = self.parse_expression()?;
         let block = self.parse_block()?;
         Ok(expr!(ExprKind::While(cond, block), pos))
     }
 
+    /// Parses `let pattern = value` after `if` or `while`.
+    fn parse_let_condition(&mut self) -> Result<(Box<Pattern>, Box<Expr>), MsgWithPos> {
+        self.expect_token(TokenKind::Let)?;
+        let pat = self.parse_pattern()?;
+        self.expect_token(TokenKind::Eq)?;
+        let value = self.parse_expression()?;
+        Ok((pat, value))
+    }
+
+    fn parse_for(&mut self) -> EResult {
+        let pos = self.expect_token(TokenKind::For)?.position;
+        let pat = self.parse_pattern()?;
+        self.expect_token(TokenKind::In)?;
+        let iter = self.parse_expression()?;
+        let block = self.parse_block()?;
+        Ok(expr!(ExprKind::For(pat, iter, block), pos))
+    }
+
     fn parse_if(&mut self) -> EResult {
         let pos = self.expect_token(TokenKind::If)?.position;
-        let cond = self.parse_expression()?;
+        let (pat, cond) = if self.token.is(TokenKind::Let) {
+            let (pat, value) = self.parse_let_condition()?;
+            (Some(pat), value)
+        } else {
+            (None, self.parse_expression()?)
+        };
         let then_block = self.parse_expression()?;
         let else_block = if self.token.is(TokenKind::Else) {
             self.advance_token()?;
@@ -209,16 +522,29 @@ impl<'a> Parser<'a> {
             None
         };
 
-        Ok(expr!(ExprKind::If(cond, then_block, else_block), pos))
+        match pat {
+            Some(pat) => Ok(expr!(
+                ExprKind::IfLet(pat, cond, then_block, else_block),
+                pos
+            )),
+            None => Ok(expr!(ExprKind::If(cond, then_block, else_block), pos)),
+        }
     }
 
     fn parse_block(&mut self) -> EResult {
         let pos = self.expect_token(TokenKind::LBrace)?.position;
         let mut exprs = vec![];
+        // Macros defined in the block are only visible until its end.
+        let macros = self.macros.clone();
         while !self.token.is(TokenKind::RBrace) && !self.token.is_eof() {
+            if self.token.is(TokenKind::Macro) {
+                self.parse_macro()?;
+                continue;
+            }
             let expr = self.parse_expression()?;
             exprs.push(expr);
         }
+        self.macros = macros;
         self.expect_token(TokenKind::RBrace)?;
         Ok(expr!(ExprKind::Block(exprs), pos))
     }
@@ -226,6 +552,34 @@ impl<'a> Parser<'a> {
     fn create_binary(&mut self, tok: Token, left: Box<Expr>, right: Box<Expr>) -> Box<Expr> {
         let op = match tok.kind {
             TokenKind::Eq => return expr!(ExprKind::Assign(left, right), tok.position),
+            TokenKind::AddEq
+            | TokenKind::SubEq
+            | TokenKind::MulEq
+            | TokenKind::DivEq
+            | TokenKind::ModEq
+            | TokenKind::LtLtEq
+            | TokenKind::GtGtEq
+            | TokenKind::GtGtGtEq
+            | TokenKind::BitAndEq
+            | TokenKind::BitOrEq
+            | TokenKind::CaretEq => {
+                let name = tok.name();
+                let op = name[..name.len() - 1].to_owned();
+                return expr!(ExprKind::CompoundAssign(op, left, right), tok.position);
+            }
+            // `x |> f(a)` is `f(x, a)` and `x |> f` is `f(x)`.
+            TokenKind::Pipe => {
+                return match right.expr {
+                    ExprKind::Call(callee, mut args) => {
+                        args.insert(0, left);
+                        expr!(ExprKind::Call(callee, args), tok.position)
+                    }
+                    callee => expr!(
+                        ExprKind::Call(expr!(callee, right.pos), vec![left]),
+                        tok.position
+                    ),
+                };
+            }
             TokenKind::Or => "||",
             TokenKind::And => "&&",
             TokenKind::BitOr => "|",
@@ -243,7 +597,13 @@ impl<'a> Parser<'a> {
             TokenKind::Div => "/",
             TokenKind::LtLt => "<<",
             TokenKind::GtGt => ">>",
+            TokenKind::GtGtGt => ">>>",
             TokenKind::Mod => "%",
+            TokenKind::QuestionQuestion => "??",
+            TokenKind::DotDot | TokenKind::DotDotEq => {
+                let inclusive = tok.is(TokenKind::DotDotEq);
+                return expr!(ExprKind::Range(left, right, inclusive), tok.position);
+            }
             _ => unimplemented!(),
         };
 
@@ -256,16 +616,33 @@ impl<'a> Parser<'a> {
             let right_precedence = match self.token.kind {
                 TokenKind::Or => 1,
                 TokenKind::And => 2,
-                TokenKind::Eq => 3,
+                TokenKind::Eq
+                | TokenKind::AddEq
+                | TokenKind::SubEq
+                | TokenKind::MulEq
+                | TokenKind::DivEq
+                | TokenKind::ModEq
+                | TokenKind::LtLtEq
+                | TokenKind::GtGtEq
+                | TokenKind::GtGtGtEq
+                | TokenKind::BitAndEq
+                | TokenKind::BitOrEq
+                | TokenKind::CaretEq => 3,
+                TokenKind::QuestionQuestion => 4,
+                TokenKind::BitOr => BIT_OR_PRECEDENCE,
+                TokenKind::Caret => 6,
+                TokenKind::BitAnd => 7,
                 TokenKind::EqEq
                 | TokenKind::Ne
                 | TokenKind::Lt
                 | TokenKind::Le
                 | TokenKind::Gt
-                | TokenKind::Ge => 4,
-                TokenKind::BitOr | TokenKind::BitAnd | TokenKind::Caret => 6,
-                TokenKind::LtLt | TokenKind::GtGt | TokenKind::Add | TokenKind::Sub => 8,
-                TokenKind::Mul | TokenKind::Div | TokenKind::Mod => 9,
+                | TokenKind::Ge => 8,
+                TokenKind::Pipe => 9,
+                TokenKind::DotDot | TokenKind::DotDotEq => 10,
+                TokenKind::LtLt | TokenKind::GtGt | TokenKind::GtGtGt => 11,
+                TokenKind::Add | TokenKind::Sub => 12,
+                TokenKind::Mul | TokenKind::Div | TokenKind::Mod => 13,
                 _ => {
                     return Ok(left);
                 }
@@ -284,17 +661,23 @@ impl<'a> Parser<'a> {
 
     pub fn parse_unary(&mut self) -> EResult {
         match self.token.kind {
-            TokenKind::Add | TokenKind::Sub | TokenKind::Not => {
+            TokenKind::Add | TokenKind::Sub | TokenKind::Not | TokenKind::Tilde => {
                 let tok = self.advance_token()?;
                 let op = match tok.kind {
                     TokenKind::Add => String::from("+"),
                     TokenKind::Sub => String::from("-"),
                     TokenKind::Not => String::from("!"),
+                    TokenKind::Tilde => String::from("~"),
                     _ => unreachable!(),
                 };
                 let expr = self.parse_primary()?;
                 Ok(expr!(ExprKind::Unop(op, expr), tok.position))
             }
+            TokenKind::Await => {
+                let pos = self.advance_token()?.position;
+                let expr = self.parse_unary()?;
+                Ok(expr!(ExprKind::Await(expr), pos))
+            }
             _ => self.parse_primary(),
         }
     }
@@ -305,8 +688,24 @@ impl<'a> Parser<'a> {
 
     pub fn parse_primary(&mut self) -> EResult {
         let mut left = self.parse_factor()?;
+        let mut optional = false;
         loop {
             left = match self.token.kind {
+                TokenKind::QuestionDot => {
+                    optional = true;
+                    let tok = self.advance_token()?;
+                    let ident = self.expect_identifier()?;
+                    let object = expr!(ExprKind::SafeNav(left), tok.position);
+                    expr!(ExprKind::Access(object, ident), tok.position)
+                }
+                TokenKind::QuestionBracket => {
+                    optional = true;
+                    let tok = self.advance_token()?;
+                    let index = self.parse_expression()?;
+                    self.expect_token(TokenKind::RBracket)?;
+                    let object = expr!(ExprKind::SafeNav(left), tok.position);
+                    expr!(ExprKind::ArrayIndex(object, index), tok.position)
+                }
                 TokenKind::Dot => {
                     let tok = self.advance_token()?;
                     let ident = self.expect_identifier()?;
@@ -320,14 +719,11 @@ impl<'a> Parser<'a> {
                 }
                 _ => {
                     if self.token.is(TokenKind::LParen) {
-                        let expr = left;
-
                         self.expect_token(TokenKind::LParen)?;
 
-                        let args =
-                            self.parse_comma_list(TokenKind::RParen, |p| p.parse_expression())?;
-
-                        expr!(ExprKind::Call(expr, args), expr.pos)
+                        self.parse_call(left)?
+                    } else if optional {
+                        return Ok(expr!(ExprKind::Optional(left), left.pos));
                     } else {
                         return Ok(left);
                     }
@@ -399,6 +795,7 @@ impl<'a> Parser<'a> {
     }
     fn advance_token(&mut self) -> Result<Token, MsgWithPos> {
         let tok = self.lexer.read_token()?;
+        self.last_pos = self.token.position;
 
         Ok(mem::replace(&mut self.token, tok))
     }
@@ -408,8 +805,9 @@ impl<'a> Parser<'a> {
         let params = if tok.kind == TokenKind::Or {
             vec![]
         } else {
-            self.parse_comma_list(TokenKind::BitOr, |f| f.parse_arg())?
+            self.parse_comma_list(TokenKind::BitOr, |f| f.parse_arg(true))?
         };
+        check_params(tok.position, &params)?;
 
         let block = self.parse_expression()?;
         Ok(expr!(ExprKind::Lambda(params, block), tok.position))
@@ -444,23 +842,385 @@ impl<'a> Parser<'a> {
         })
         .map(Box::new)
     }
+    /// Parses `actor Name(params) [supervised] { state x = init ... on
+    /// pattern -> handler ... }` and lowers it to `function Name(params)`,
+    /// which spawns a process running the handlers in a `receive` loop:
+    ///
+    /// ```text
+    /// Process.spawn(|| {
+    ///     var x = init
+    ///     let reply = |to, value| Process.send(to, [:Name, value])
+    ///     var running = true
+    ///     while running {
+    ///         running = receive { pattern -> handler, ... } != :stop
+    ///     }
+    /// })
+    /// ```
+    ///
+    /// A handler evaluating to `:stop` ends the actor. A `supervised` actor
+    /// catches what its handlers throw and reports it to the process that
+    /// spawned it:
+    ///
+    /// ```text
+    /// let parent = Process.current()
+    /// Process.spawn(|| {
+    ///     ...
+    ///     try {
+    ///         while running { ... }
+    ///     } catch error {
+    ///         Process.send(parent, [:crash, Process.current(), error])
+    ///     }
+    /// })
+    /// ```
+    fn parse_actor(&mut self) -> EResult {
+        let pos = self.expect_token(TokenKind::Actor)?.position;
+        let name = self.expect_identifier()?;
+        let params = if self.token.is(TokenKind::LParen) {
+            self.advance_token()?;
+            let params =
+                self.parse_comma_list(TokenKind::RParen, |parser| parser.parse_arg(false))?;
+            check_params(pos, &params)?;
+            params
+        } else {
+            vec![]
+        };
+        let supervised = self.token.kind == TokenKind::Identifier("supervised".to_owned());
+        if supervised {
+            self.advance_token()?;
+        }
+        self.expect_token(TokenKind::LBrace)?;
+        let mut body = vec![];
+        let mut handlers = vec![];
+        self.parse_list(TokenKind::RBrace, |p| {
+            let pos = p.token.position;
+            match &p.token.kind {
+                TokenKind::Identifier(item) if item == "state" => {
+                    p.advance_token()?;
+                    let state = p.expect_identifier()?;
+                    p.expect_token(TokenKind::Eq)?;
+                    let init = p.parse_expression()?;
+                    body.push(expr!(ExprKind::Var(true, state, Some(init)), pos));
+                }
+                TokenKind::Identifier(item) if item == "on" => {
+                    p.advance_token()?;
+                    handlers.push(p.parse_match_arm()?);
+                }
+                _ => {
+                    return Err(MsgWithPos::new(
+                        pos,
+                        Msg::ExpectedActorElement(p.token.name()),
+                    ))
+                }
+            }
+            if p.token.is(TokenKind::Comma) {
+                p.advance_token()?;
+            }
+            Ok(())
+        })?;
+
+        let ident = |name: &str| expr!(ExprKind::Ident(name.to_owned()), pos);
+        let process = |method: &str, args: Vec<Box<Expr>>| {
+            let callee = expr!(ExprKind::Access(ident("Process"), method.to_owned()), pos);
+            expr!(ExprKind::Call(callee, args), pos)
+        };
+        let reply = expr!(
+            ExprKind::Lambda(
+                vec![
+                    Arg::Ident(false, "to".to_owned()),
+                    Arg::Ident(false, "value".to_owned()),
+                ],
+                process(
+                    "send",
+                    vec![
+                        ident("to"),
+                        expr!(
+                            ExprKind::Array(vec![
+                                expr!(ExprKind::ConstAtom(name.clone()), pos),
+                                ident("value"),
+                            ]),
+                            pos
+                        ),
+                    ],
+                ),
+            ),
+            pos
+        );
+        body.push(expr!(
+            ExprKind::Let(
+                false,
+                Box::new(Pattern {
+                    decl: PatternDecl::Ident("reply".to_owned()),
+                    pos,
+                }),
+                reply
+            ),
+            pos
+        ));
+        // Generated names can't clash with the ones used by handlers.
+        let running = "actor$running";
+        body.push(expr!(
+            ExprKind::Var(
+                true,
+                running.to_owned(),
+                Some(expr!(ExprKind::ConstBool(true), pos))
+            ),
+            pos
+        ));
+        let handled = expr!(
+            ExprKind::BinOp(
+                expr!(ExprKind::Receive(handlers, None), pos),
+                "!=".to_owned(),
+                expr!(ExprKind::ConstAtom("stop".to_owned()), pos)
+            ),
+            pos
+        );
+        let lp = expr!(
+            ExprKind::While(
+                ident(running),
+                expr!(
+                    ExprKind::Block(vec![expr!(ExprKind::Assign(ident(running), handled), pos)]),
+                    pos
+                )
+            ),
+            pos
+        );
+        let mut spawn = vec![];
+        if supervised {
+            let parent = "actor$parent";
+            spawn.push(expr!(
+                ExprKind::Let(
+                    false,
+                    Box::new(Pattern {
+                        decl: PatternDecl::Ident(parent.to_owned()),
+                        pos,
+                    }),
+                    process("current", vec![])
+                ),
+                pos
+            ));
+            let error = "actor$error";
+            let crash = expr!(
+                ExprKind::Array(vec![
+                    expr!(ExprKind::ConstAtom("crash".to_owned()), pos),
+                    process("current", vec![]),
+                    ident(error),
+                ]),
+                pos
+            );
+            let report = process("send", vec![ident(parent), crash]);
+            body.push(expr!(
+                ExprKind::Try(
+                    expr!(ExprKind::Block(vec![lp]), pos),
+                    vec![(
+                        Box::new(Pattern {
+                            decl: PatternDecl::Ident(error.to_owned()),
+                            pos,
+                        }),
+                        None,
+                        expr!(ExprKind::Block(vec![report]), pos),
+                    )],
+                    None
+                ),
+                pos
+            ));
+        } else {
+            body.push(lp);
+        }
+        let actor = expr!(
+            ExprKind::Lambda(vec![], expr!(ExprKind::Block(body), pos)),
+            pos
+        );
+        spawn.push(process("spawn", vec![actor]));
+        Ok(expr!(
+            ExprKind::Function(Some(name), params, expr!(ExprKind::Block(spawn), pos)),
+            pos
+        ))
+    }
+
+    /// Parses `macro name(params) { template }`. The template is kept as
+    /// tokens until the macro is called, the definition itself leaves
+    /// nothing in the statement list.
+    fn parse_macro(&mut self) -> Result<(), MsgWithPos> {
+        self.expect_token(TokenKind::Macro)?;
+        let name = self.expect_identifier()?;
+        self.expect_token(TokenKind::LParen)?;
+        let params = self.parse_comma_list(TokenKind::RParen, |p| p.expect_identifier())?;
+        let pos = self.expect_token(TokenKind::LBrace)?.position;
+        let template = self.parse_token_tree(TokenKind::RBrace)?;
+        let introduced = self
+            .template_bindings(pos, &template)
+            .into_iter()
+            .filter(|name| !params.contains(name))
+            .collect();
+        self.macros.insert(
+            name,
+            MacroDef {
+                params,
+                template,
+                introduced,
+            },
+        );
+        Ok(())
+    }
+
+    /// Names bound anywhere in `template`: by `let` and `var`, patterns of
+    /// `for`, `match`, `if let`, `receive` and `catch`, and parameters.
+    /// A template that only parses once the arguments are substituted falls
+    /// back to the names directly after `let`, `var`, `for` and `catch`.
+    fn template_bindings(&self, pos: Position, template: &[Token]) -> Vec<String> {
+        let mut tokens = vec![Token::new(TokenKind::LBrace, pos)];
+        tokens.extend(template.iter().cloned());
+        tokens.push(Token::new(TokenKind::RBrace, pos));
+        let mut ast = vec![];
+        let mut parser = Parser::new(Reader::from_string(""), &mut ast);
+        parser.macros = self.macros.clone();
+        parser.expanding = self.expanding;
+        parser.lexer.push_tokens(tokens);
+        match parser.advance_token().and_then(|_| parser.parse_block()) {
+            Ok(block) => block.bindings(),
+            Err(_) => template
+                .windows(2)
+                .filter_map(|pair| match (&pair[0].kind, &pair[1].kind) {
+                    (
+                        TokenKind::Let | TokenKind::Var | TokenKind::For | TokenKind::Catch,
+                        TokenKind::Identifier(name),
+                    ) => Some(name.clone()),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Reads the tokens up to the `close` matching an already consumed
+    /// opening bracket and consumes `close`.
+    fn parse_token_tree(&mut self, close: TokenKind) -> Result<Vec<Token>, MsgWithPos> {
+        let mut depth = 0;
+        let mut tokens = vec![];
+        loop {
+            if depth == 0 && self.token.is(close.clone()) {
+                self.advance_token()?;
+                return Ok(tokens);
+            }
+            match self.token.kind {
+                TokenKind::LParen
+                | TokenKind::LBracket
+                | TokenKind::QuestionBracket
+                | TokenKind::LBrace => depth += 1,
+                TokenKind::RParen | TokenKind::RBracket | TokenKind::RBrace if depth > 0 => {
+                    depth -= 1
+                }
+                TokenKind::RParen | TokenKind::RBracket | TokenKind::RBrace | TokenKind::End => {
+                    return Err(MsgWithPos::new(
+                        self.token.position,
+                        Msg::ExpectedToken(close.name().into(), self.token.name()),
+                    ))
+                }
+                _ => (),
+            }
+            tokens.push(self.advance_token()?);
+        }
+    }
+
+    /// Parses `name!(args)`. Each argument is a token tree replacing its
+    /// parameter in the template, the result is parsed as a block.
+    fn parse_macro_call(&mut self) -> EResult {
+        let pos = self.token.position;
+        let name = self.expect_identifier()?;
+        self.expect_token(TokenKind::Not)?;
+        self.expect_token(TokenKind::LParen)?;
+        let mut args = vec![];
+        let mut arg = vec![];
+        let mut depth = 0;
+        for tok in self.parse_token_tree(TokenKind::RParen)? {
+            match tok.kind {
+                TokenKind::LParen
+                | TokenKind::LBracket
+                | TokenKind::QuestionBracket
+                | TokenKind::LBrace => depth += 1,
+                TokenKind::RParen | TokenKind::RBracket | TokenKind::RBrace => depth -= 1,
+                TokenKind::Comma if depth == 0 => {
+                    args.push(mem::take(&mut arg));
+                    continue;
+                }
+                _ => (),
+            }
+            arg.push(tok);
+        }
+        if !arg.is_empty() {
+            args.push(arg);
+        }
+        let def = self.macros[&name].clone();
+        if args.len() != def.params.len() {
+            return Err(MsgWithPos::new(
+                pos,
+                Msg::MacroArgs(name, def.params.len(), args.len()),
+            ));
+        }
+        if self.expanding == MAX_EXPANSION_DEPTH {
+            return Err(MsgWithPos::new(pos, Msg::MacroRecursion(name)));
+        }
+
+        self.expansions += 1;
+        let mut expansion = vec![Token::new(TokenKind::LBrace, pos)];
+        for (at, tok) in def.template.iter().enumerate() {
+            if let TokenKind::Identifier(ident) = &tok.kind {
+                if let Some(i) = def.params.iter().position(|p| p == ident) {
+                    expansion.extend(args[i].iter().cloned());
+                    continue;
+                }
+                // Field names after `.` or before `:` are not bindings.
+                let field = (at > 0
+                    && matches!(
+                        def.template[at - 1].kind,
+                        TokenKind::Dot | TokenKind::QuestionDot
+                    ))
+                    || matches!(def.template.get(at + 1), Some(next) if is_label(tok.position.line, next));
+                if def.introduced.contains(ident) && !field {
+                    // `$` can't appear in identifiers written by hand.
+                    let fresh = format!("{}${}", ident, self.expansions);
+                    expansion.push(Token::new(TokenKind::Identifier(fresh), tok.position));
+                    continue;
+                }
+            }
+            expansion.push(tok.clone());
+        }
+        expansion.push(Token::new(TokenKind::RBrace, self.last_pos));
+        // The token after the call is read again once the expansion is parsed.
+        expansion.push(self.token.clone());
+        self.lexer.push_tokens(expansion);
+        self.advance_token()?;
+
+        self.expanding += 1;
+        let block = self.parse_block();
+        self.expanding -= 1;
+        let block = block.map_err(|err| match err.msg {
+            // Reported once, at the outermost call.
+            Msg::MacroRecursion(_) => MsgWithPos::new(pos, err.msg),
+            msg => MsgWithPos::new(pos, Msg::InMacro(name.clone(), err.pos, Box::new(msg))),
+        })?;
+        Ok(expr!(ExprKind::Expansion(name, block), pos))
+    }
+
+    fn parse_parallel(&mut self) -> EResult {
+        let pos = self.expect_token(TokenKind::Parallel)?.position;
+        self.expect_token(TokenKind::LBrace)?;
+        let bindings = self.parse_list(TokenKind::RBrace, |p| {
+            let pat = p.parse_pattern()?;
+            p.expect_token(TokenKind::Eq)?;
+            let value = p.parse_expression()?;
+            if p.token.is(TokenKind::Semicolon) || p.token.is(TokenKind::Comma) {
+                p.advance_token()?;
+            }
+            Ok((pat, value))
+        })?;
+        Ok(expr!(ExprKind::Parallel(bindings), pos))
+    }
+
     fn parse_match(&mut self) -> EResult {
         let pos = self.expect_token(TokenKind::Match)?.position;
         let e = self.parse_expression()?;
         self.expect_token(TokenKind::LBrace)?;
-        let list = self.parse_comma_list(TokenKind::RBrace, |parser: &mut Parser| {
-            let pat = parser.parse_pattern()?;
-            let when_clause = if parser.token.is(TokenKind::When) || parser.token.is(TokenKind::Or)
-            {
-                parser.advance_token()?;
-                Some(parser.parse_expression()?)
-            } else {
-                None
-            };
-            parser.expect_token(TokenKind::Arrow)?;
-            let expr = parser.parse_expression()?;
-            Ok((pat, when_clause, expr))
-        })?;
+        let list = self.parse_comma_list(TokenKind::RBrace, |parser| parser.parse_match_arm())?;
 
         Ok(Expr {
             expr: ExprKind::Match(e, list),
@@ -469,7 +1229,135 @@ impl<'a> Parser<'a> {
         .map(|x| Box::new(x))
     }
 
+    fn parse_match_arm(&mut self) -> Result<MatchArm, MsgWithPos> {
+        let pat = self.parse_pattern()?;
+        let when_clause = if self.token.is(TokenKind::When) || self.token.is(TokenKind::Or) {
+            self.advance_token()?;
+            Some(self.parse_expression()?)
+        } else {
+            None
+        };
+        self.expect_token(TokenKind::Arrow)?;
+        let expr = self.parse_expression()?;
+        Ok((pat, when_clause, expr))
+    }
+
+    fn parse_receive(&mut self) -> EResult {
+        let pos = self.expect_token(TokenKind::Receive)?.position;
+        self.expect_token(TokenKind::LBrace)?;
+        let mut arms = vec![];
+        let mut after = None;
+        while !self.token.is(TokenKind::RBrace) {
+            if self.is_after_clause()? {
+                // `after` ends the arms.
+                self.advance_token()?;
+                let timeout = self.parse_expression()?;
+                self.expect_token(TokenKind::Arrow)?;
+                after = Some((timeout, self.parse_expression()?));
+                if self.token.is(TokenKind::Comma) {
+                    self.advance_token()?;
+                }
+                break;
+            }
+            arms.push(self.parse_match_arm()?);
+            if !self.token.is(TokenKind::RBrace) {
+                self.expect_token(TokenKind::Comma)?;
+            }
+        }
+        self.expect_token(TokenKind::RBrace)?;
+        Ok(expr!(ExprKind::Receive(arms, after), pos))
+    }
+
+    /// `after` is only a keyword at the start of a `receive` arm, where a
+    /// pattern binding a variable named `after` would be followed by `->`
+    /// or a guard instead.
+    fn is_after_clause(&mut self) -> Result<bool, MsgWithPos> {
+        if self.token.kind != TokenKind::Identifier("after".to_owned()) {
+            return Ok(false);
+        }
+        Ok(!matches!(
+            self.lexer.peek_token(0)?.kind,
+            TokenKind::Arrow
+                | TokenKind::When
+                | TokenKind::Or
+                | TokenKind::BitOr
+                | TokenKind::At
+                | TokenKind::Sep
+        ))
+    }
+
     fn parse_pattern(&mut self) -> Result<Box<Pattern>, MsgWithPos> {
+        let pos = self.token.position;
+        let first = self.parse_cons_pattern()?;
+        if !self.token.is(TokenKind::BitOr) {
+            return Ok(first);
+        }
+        let names = sorted(first.bindings());
+        let mut alts = vec![first];
+        while self.token.is(TokenKind::BitOr) {
+            self.advance_token()?;
+            let alt = self.parse_cons_pattern()?;
+            if sorted(alt.bindings()) != names {
+                return Err(MsgWithPos::new(
+                    alt.pos,
+                    Msg::Custom(format!(
+                        "alternative binds ({}) but the first alternative binds ({})",
+                        alt.bindings().join(", "),
+                        names.join(", ")
+                    )),
+                ));
+            }
+            alts.push(alt);
+        }
+        Ok(Box::new(Pattern {
+            decl: PatternDecl::Or(alts),
+            pos,
+        }))
+    }
+
+    fn parse_cons_pattern(&mut self) -> Result<Box<Pattern>, MsgWithPos> {
+        let head = self.parse_range_pattern()?;
+        if self.token.is(TokenKind::Sep) {
+            let pos = self.advance_token()?.position;
+            let tail = self.parse_cons_pattern()?;
+            return Ok(Box::new(Pattern {
+                decl: PatternDecl::Cons(head, tail),
+                pos,
+            }));
+        }
+        Ok(head)
+    }
+
+    fn parse_range_pattern(&mut self) -> Result<Box<Pattern>, MsgWithPos> {
+        let lo = self.parse_simple_pattern()?;
+        let inclusive = match self.token.kind {
+            TokenKind::DotDot => false,
+            TokenKind::DotDotEq => true,
+            _ => return Ok(lo),
+        };
+        self.advance_token()?;
+        let hi = self.parse_simple_pattern()?;
+        for bound in [&lo, &hi].iter() {
+            match bound.decl {
+                PatternDecl::ConstInt(_)
+                | PatternDecl::ConstFloat(_)
+                | PatternDecl::ConstChar(_) => (),
+                _ => {
+                    return Err(MsgWithPos::new(
+                        bound.pos,
+                        Msg::Custom("range pattern bounds must be literals".to_owned()),
+                    ))
+                }
+            }
+        }
+        let pos = lo.pos;
+        Ok(Box::new(Pattern {
+            decl: PatternDecl::Range(lo, hi, inclusive),
+            pos,
+        }))
+    }
+
+    fn parse_simple_pattern(&mut self) -> Result<Box<Pattern>, MsgWithPos> {
         let pos = self.token.position;
         match self.token.kind {
             TokenKind::Underscore => {
@@ -481,32 +1369,106 @@ impl<'a> Parser<'a> {
             }
             TokenKind::LitInt { .. } => self.plit_int(),
             TokenKind::LitFloat(_) => self.plit_float(),
+            TokenKind::LitChar(_) => self.plit_char(),
+            TokenKind::True | TokenKind::False => {
+                let tok = self.advance_token()?;
+                Ok(Box::new(Pattern {
+                    decl: PatternDecl::ConstBool(tok.is(TokenKind::True)),
+                    pos,
+                }))
+            }
+            TokenKind::Nil => {
+                self.advance_token()?;
+                Ok(Box::new(Pattern {
+                    decl: PatternDecl::Nil,
+                    pos,
+                }))
+            }
+            TokenKind::Sub => {
+                self.advance_token()?;
+                let mut pat = match self.token.kind {
+                    TokenKind::LitInt { .. } => self.plit_int()?,
+                    TokenKind::LitFloat(_) => self.plit_float()?,
+                    _ => {
+                        return Err(MsgWithPos::new(
+                            self.token.position,
+                            Msg::ExpectedToken("number".into(), self.token.name()),
+                        ))
+                    }
+                };
+                pat.decl = match pat.decl {
+                    PatternDecl::ConstInt(i) => PatternDecl::ConstInt(-i),
+                    PatternDecl::ConstFloat(f) => PatternDecl::ConstFloat(-f),
+                    _ => unreachable!(),
+                };
+                pat.pos = pos;
+                Ok(pat)
+            }
             TokenKind::String(_) => self.plit_str(),
-            TokenKind::Identifier(_) => self.pident(),
+            TokenKind::Atom(_) => self.plit_atom(),
+            TokenKind::Identifier(_) => {
+                let pat = self.pident()?;
+                if !self.token.is(TokenKind::At) {
+                    return Ok(pat);
+                }
+                self.advance_token()?;
+                let name = match pat.decl {
+                    PatternDecl::Ident(name) => name,
+                    _ => unreachable!(),
+                };
+                let inner = self.parse_simple_pattern()?;
+                Ok(Box::new(Pattern {
+                    decl: PatternDecl::Bind(name, inner),
+                    pos,
+                }))
+            }
+            TokenKind::LParen => {
+                self.advance_token()?;
+                let pat = self.parse_pattern()?;
+                self.expect_token(TokenKind::RParen)?;
+                Ok(pat)
+            }
             TokenKind::LBracket => self.parray(),
             TokenKind::LBrace => self.precord(),
 
             TokenKind::DotDot => {
                 let pos = self.advance_token()?.position;
+                let name = if let TokenKind::Identifier(_) = self.token.kind {
+                    Some(self.expect_identifier()?)
+                } else {
+                    None
+                };
                 Ok(Pattern {
-                    decl: PatternDecl::Rest,
+                    decl: PatternDecl::Rest(name),
                     pos: pos,
                 })
                 .map(|x| Box::new(x))
             }
-            _ => unimplemented!(),
+            _ => Err(MsgWithPos::new(
+                pos,
+                Msg::ExpectedPattern(self.token.name()),
+            )),
         }
     }
 
     pub fn parse_factor(&mut self) -> EResult {
         let expr = match self.token.kind {
             TokenKind::Fun => self.parse_function(),
+            TokenKind::Async => self.parse_async(),
             TokenKind::LParen => self.parse_parentheses(),
+            TokenKind::LBracket => self.parse_array(),
             TokenKind::LitChar(_) => self.lit_char(),
             TokenKind::LitInt(_, _, _) => self.lit_int(),
             TokenKind::LitFloat(_) => self.lit_float(),
             TokenKind::String(_) => self.lit_str(),
-            TokenKind::Identifier(_) => self.ident(),
+            TokenKind::Atom(_) => self.lit_atom(),
+            TokenKind::Identifier(ref name) => {
+                if self.macros.contains_key(name) && self.lexer.peek_token(0)?.is(TokenKind::Not) {
+                    self.parse_macro_call()
+                } else {
+                    self.ident()
+                }
+            }
             TokenKind::This => self.parse_self(),
             TokenKind::BitOr | TokenKind::Or => self.parse_lambda(),
             TokenKind::True => self.parse_bool_literal(),
@@ -517,18 +1479,8 @@ impl<'a> Parser<'a> {
                 self.advance_token()?;
                 if self.token.is(TokenKind::LBrace) {
                     self.expect_token(TokenKind::LBrace)?;
-                    let list = self.parse_comma_list(TokenKind::RBrace, |p| {
-                        let name = p.expect_identifier()?;
-                        let value = if p.token.is(TokenKind::Colon) {
-                            p.advance_token()?;
-                            Some(p.parse_expression()?)
-                        } else {
-                            None
-                        };
-
-                        Ok((name, value))
-                    });
-                    Ok(expr!(ExprKind::NewObject(list?), pos))
+                    let list = self.parse_object_fields()?;
+                    Ok(expr!(ExprKind::NewObject(list), pos))
                 } else {
                     let call = self.parse_expression()?;
                     if let ExprKind::Call { .. } = call.expr {
@@ -611,6 +1563,16 @@ impl<'a> Parser<'a> {
         }
     }
 
+    fn lit_atom(&mut self) -> EResult {
+        let tok = self.advance_token()?;
+        let pos = tok.position;
+        if let TokenKind::Atom(s) = tok.kind {
+            Ok(expr!(ExprKind::ConstAtom(s), pos))
+        } else {
+            unreachable!()
+        }
+    }
+
     fn ident(&mut self) -> EResult {
         let pos = self.token.position;
         let ident = self.expect_identifier()?;
@@ -670,6 +1632,19 @@ impl<'a> Parser<'a> {
         }
     }
 
+    fn plit_atom(&mut self) -> Result<Box<Pattern>, MsgWithPos> {
+        let tok = self.advance_token()?;
+        let pos = tok.position;
+        if let TokenKind::Atom(s) = tok.kind {
+            Ok(Box::new(Pattern {
+                decl: PatternDecl::ConstAtom(s),
+                pos,
+            }))
+        } else {
+            unreachable!()
+        }
+    }
+
     fn pident(&mut self) -> Result<Box<Pattern>, MsgWithPos> {
         let pos = self.token.position;
         let ident = self.expect_identifier()?;
@@ -685,25 +1660,34 @@ impl<'a> Parser<'a> {
         let pos = self.token.position;
         self.expect_token(TokenKind::LBracket)?;
         let list = self.parse_comma_list(TokenKind::RBracket, |parser| parser.parse_pattern())?;
+        let decl = if list.is_empty() {
+            PatternDecl::EmptyList
+        } else {
+            PatternDecl::Array(list)
+        };
 
-        Ok(Pattern {
-            decl: PatternDecl::Array(list),
-            pos,
-        })
-        .map(|x| Box::new(x))
+        Ok(Box::new(Pattern { decl, pos }))
     }
 
     fn precord(&mut self) -> Result<Box<Pattern>, MsgWithPos> {
         let pos = self.expect_token(TokenKind::LBrace)?.position;
         let record = self.parse_comma_list(TokenKind::RBrace, |parser| {
+            let pos = parser.token.position;
             let name = parser.expect_identifier()?;
-            let pattern = if parser.token.is(TokenKind::Colon) {
-                parser.expect_token(TokenKind::Colon)?;
-                Some(parser.parse_pattern()?)
-            } else {
-                None
-            };
-            Ok((name, pattern))
+            if is_label(pos.line, &parser.token) {
+                parser.expect_label()?;
+                return Ok((name, Some(parser.parse_pattern()?)));
+            }
+            match renamed_field(&name) {
+                Some(field) => Ok((
+                    field,
+                    Some(Box::new(Pattern {
+                        decl: PatternDecl::Ident(name),
+                        pos,
+                    })),
+                )),
+                None => Ok((name, None)),
+            }
         })?;
 
         Ok(Pattern {
@@ -713,3 +1697,766 @@ impl<'a> Parser<'a> {
         .map(|x| Box::new(x))
     }
 }
+
+/// Whether `next`, following a name on line `line`, is the `:` of
+/// `name: value`. `x:y` and `x :y` lex as `x` and the atom `:y`, which is
+/// a label followed by `y` when it is on the same line as the name.
+fn is_label(line: u32, next: &Token) -> bool {
+    match next.kind {
+        TokenKind::Colon => true,
+        TokenKind::Atom(_) => next.position.line == line,
+        _ => false,
+    }
+}
+
+/// The field a shorthand `{name}` stands for when a macro expansion renamed
+/// `name`: `{x$1}` is the field `x` bound to or read from `x$1`.
+fn renamed_field(name: &str) -> Option<String> {
+    name.find('$').map(|at| name[..at].to_owned())
+}
+
+/// `...rest` must be the last parameter and a parameter with a default
+/// can only be followed by more defaults.
+fn check_params(pos: Position, params: &[Arg]) -> Result<(), MsgWithPos> {
+    let mut default = false;
+    for (i, param) in params.iter().enumerate() {
+        let msg = match param {
+            Arg::Rest(name) if i + 1 != params.len() => {
+                format!("rest parameter '{}' must be the last parameter", name)
+            }
+            Arg::Default(..) => {
+                default = true;
+                continue;
+            }
+            Arg::Rest(_) => continue,
+            _ if default => "parameter without a default after a parameter with one".to_owned(),
+            _ => continue,
+        };
+        return Err(MsgWithPos::new(pos, Msg::Custom(msg)));
+    }
+    Ok(())
+}
+
+fn sorted(mut names: Vec<String>) -> Vec<String> {
+    names.sort();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(code: &str) -> Vec<Expr> {
+        let mut ast = vec![];
+        Parser::new(Reader::from_string(code), &mut ast)
+            .parse()
+            .unwrap();
+        ast.into_iter().map(|e| *e).collect()
+    }
+
+    fn arm_patterns(code: &str) -> Vec<PatternDecl> {
+        match &parse(code)[0].expr {
+            ExprKind::Match(_, arms) => arms.iter().map(|(p, _, _)| p.decl.clone()).collect(),
+            e => panic!("match expected, got {:?}", e),
+        }
+    }
+
+    fn pat(decl: PatternDecl) -> Box<Pattern> {
+        Box::new(Pattern {
+            decl,
+            pos: Position::new(0, 0),
+        })
+    }
+
+    fn strip(decl: PatternDecl) -> PatternDecl {
+        let s = |p: &Box<Pattern>| pat(strip(p.decl.clone()));
+        match decl {
+            PatternDecl::Cons(h, t) => PatternDecl::Cons(s(&h), s(&t)),
+            PatternDecl::Array(v) => PatternDecl::Array(v.iter().map(s).collect()),
+            PatternDecl::Or(v) => PatternDecl::Or(v.iter().map(s).collect()),
+            PatternDecl::Bind(n, p) => PatternDecl::Bind(n, s(&p)),
+            PatternDecl::Range(lo, hi, i) => PatternDecl::Range(s(&lo), s(&hi), i),
+            decl => decl,
+        }
+    }
+
+    #[test]
+    fn parse_rest_patterns() {
+        let arms = arm_patterns("match xs { [a, ..] -> 0, [..rest, z] -> 1 }");
+        assert_eq!(
+            strip(arms[0].clone()),
+            PatternDecl::Array(vec![
+                pat(PatternDecl::Ident("a".into())),
+                pat(PatternDecl::Rest(None))
+            ])
+        );
+        assert_eq!(
+            strip(arms[1].clone()),
+            PatternDecl::Array(vec![
+                pat(PatternDecl::Rest(Some("rest".into()))),
+                pat(PatternDecl::Ident("z".into()))
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_literal_patterns() {
+        let arms = arm_patterns("match x { 'c' -> 0, true -> 1, false -> 2, nil -> 3, -1 -> 4 }");
+        assert_eq!(
+            arms,
+            vec![
+                PatternDecl::ConstChar('c'),
+                PatternDecl::ConstBool(true),
+                PatternDecl::ConstBool(false),
+                PatternDecl::Nil,
+                PatternDecl::ConstInt(-1),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_invalid_pattern() {
+        let mut ast = vec![];
+        let err = Parser::new(Reader::from_string("match x { + -> 0 }"), &mut ast)
+            .parse()
+            .unwrap_err();
+        assert_eq!(err.msg, Msg::ExpectedPattern("+".into()));
+    }
+
+    #[test]
+    fn parse_or_and_bind_patterns() {
+        let arms =
+            arm_patterns(r#"match x { "quote" | "q" -> 0, all @ [a, _] | all @ [_, a] -> 1 }"#);
+        assert_eq!(
+            strip(arms[0].clone()),
+            PatternDecl::Or(vec![
+                pat(PatternDecl::ConstStr("quote".into())),
+                pat(PatternDecl::ConstStr("q".into()))
+            ])
+        );
+        let alt = |first: bool| {
+            let mut items = vec![pat(PatternDecl::Ident("a".into())), pat(PatternDecl::Pass)];
+            if !first {
+                items.reverse();
+            }
+            pat(PatternDecl::Bind(
+                "all".into(),
+                pat(PatternDecl::Array(items)),
+            ))
+        };
+        assert_eq!(
+            strip(arms[1].clone()),
+            PatternDecl::Or(vec![alt(true), alt(false)])
+        );
+    }
+
+    #[test]
+    fn or_pattern_alternatives_bind_same_names() {
+        let mut ast = vec![];
+        let code = "match x { [a, b] | [a] -> 0 }";
+        assert!(Parser::new(Reader::from_string(code), &mut ast)
+            .parse()
+            .is_err());
+    }
+
+    #[test]
+    fn parse_range_patterns() {
+        let arms = arm_patterns("match c { 0..9 -> 0, 'a'..='z' -> 1 }");
+        assert_eq!(
+            strip(arms[0].clone()),
+            PatternDecl::Range(
+                pat(PatternDecl::ConstInt(0)),
+                pat(PatternDecl::ConstInt(9)),
+                false
+            )
+        );
+        assert_eq!(
+            strip(arms[1].clone()),
+            PatternDecl::Range(
+                pat(PatternDecl::ConstChar('a')),
+                pat(PatternDecl::ConstChar('z')),
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn parse_range_expressions() {
+        match &parse("for i in 0..n - 1 { i }")[0].expr {
+            ExprKind::For(_, iter, _) => match &iter.expr {
+                ExprKind::Range(_, end, false) => assert!(end.is_binop()),
+                e => panic!("range expected, got {:?}", e),
+            },
+            e => panic!("for expected, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parse_let_conditions() {
+        let ast = parse("if let [a, b] = pair { a } else { b }\nwhile let x = it.next() { x }");
+        match &ast[0].expr {
+            ExprKind::IfLet(p, _, _, Some(_)) => assert_eq!(
+                strip(p.decl.clone()),
+                PatternDecl::Array(vec![
+                    pat(PatternDecl::Ident("a".into())),
+                    pat(PatternDecl::Ident("b".into()))
+                ])
+            ),
+            e => panic!("if let expected, got {:?}", e),
+        }
+        match &ast[1].expr {
+            ExprKind::WhileLet(p, value, _) => {
+                assert_eq!(p.decl, PatternDecl::Ident("x".into()));
+                assert!(matches!(value.expr, ExprKind::Call(..)));
+            }
+            e => panic!("while let expected, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parse_try() {
+        let code = "try { f() } catch {code: 404} { 1 } catch e when e == 2 { 2 } finally { g() }";
+        match &parse(code)[0].expr {
+            ExprKind::Try(_, catches, Some(_)) => {
+                assert_eq!(catches.len(), 2);
+                assert!(catches[0].1.is_none());
+                assert_eq!(catches[1].0.decl, PatternDecl::Ident("e".into()));
+                assert!(catches[1].1.is_some());
+            }
+            e => panic!("try expected, got {:?}", e),
+        }
+        let mut ast = vec![];
+        let err = Parser::new(Reader::from_string("try { f() }"), &mut ast)
+            .parse()
+            .unwrap_err();
+        assert_eq!(err.msg, Msg::CatchOrFinallyExpected);
+    }
+
+    #[test]
+    fn parse_defer() {
+        match &parse("{ let f = open()\ndefer f.close()\nf.read() }")[0].expr {
+            ExprKind::Block(v) => match &v[1].expr {
+                ExprKind::Defer(e) => assert!(matches!(e.expr, ExprKind::Call(..))),
+                e => panic!("defer expected, got {:?}", e),
+            },
+            e => panic!("block expected, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parse_generator() {
+        match &parse("function* g(xs) { yield 1\nyield* xs }")[0].expr {
+            ExprKind::Generator(Some(name), _, body) => {
+                assert_eq!(name, "g");
+                match &body.expr {
+                    ExprKind::Block(v) => {
+                        assert!(matches!(v[0].expr, ExprKind::Yield(_)));
+                        assert!(matches!(v[1].expr, ExprKind::YieldFrom(_)));
+                    }
+                    e => panic!("block expected, got {:?}", e),
+                }
+            }
+            e => panic!("generator expected, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parse_async() {
+        let ast = parse("async function fetch(url) { get(url) }\nawait fetch(a) + 1");
+        assert!(matches!(
+            ast[0].expr,
+            ExprKind::AsyncFunction(Some(_), _, _)
+        ));
+        match &ast[1].expr {
+            ExprKind::BinOp(lhs, op, _) => {
+                assert_eq!(op, "+");
+                assert!(matches!(lhs.expr, ExprKind::Await(_)));
+            }
+            e => panic!("binary expression expected, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parse_receive() {
+        let ast = parse("receive { [:chunk, data] -> data, :stop -> nil, after 500 -> 0 }");
+        match &ast[0].expr {
+            ExprKind::Receive(arms, Some((timeout, _))) => {
+                assert_eq!(arms.len(), 2);
+                assert_eq!(arms[1].0.decl, PatternDecl::ConstAtom("stop".into()));
+                assert_eq!(timeout.expr, ExprKind::ConstInt(500));
+            }
+            e => panic!("receive with after expected, got {:?}", e),
+        }
+        match &parse("receive { after -> after }")[0].expr {
+            ExprKind::Receive(arms, None) => {
+                assert_eq!(arms[0].0.decl, PatternDecl::Ident("after".into()))
+            }
+            e => panic!("receive expected, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parse_actor() {
+        let ast = parse(
+            "actor Counter(start) supervised {\n state count = start\n on [:add, n] -> count += n\n on [:get, from] -> reply(from, count)\n}",
+        );
+        match &ast[0].expr {
+            ExprKind::Function(Some(name), params, _) => {
+                assert_eq!(name, "Counter");
+                assert_eq!(params.len(), 1);
+            }
+            e => panic!("function expected, got {:?}", e),
+        }
+        let ast = parse("actor A {\n state n = 0\n on :reset -> n = 0\n}");
+        let ast = format!("{:?}", ast).split_whitespace().collect::<String>();
+        assert!(ast.contains("ConstAtom(\"reset\",)"));
+        let mut ast = vec![];
+        let err = Parser::new(Reader::from_string("actor A { x }"), &mut ast)
+            .parse()
+            .unwrap_err();
+        assert_eq!(err.msg, Msg::ExpectedActorElement("x".into()));
+    }
+
+    #[test]
+    fn parse_parallel() {
+        match &parse("parallel { a = f(1); [b, c] = g()\n d = 3 }")[0].expr {
+            ExprKind::Parallel(bindings) => {
+                assert_eq!(bindings.len(), 3);
+                assert_eq!(bindings[0].0.decl, PatternDecl::Ident("a".into()));
+                assert!(matches!(bindings[1].0.decl, PatternDecl::Array(_)));
+            }
+            e => panic!("parallel expected, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parse_comprehensions() {
+        match &parse("[x * y for x in xs if x > 0 for y in 0..x]")[0].expr {
+            ExprKind::Comprehension(value, clauses) => {
+                assert!(value.is_binop());
+                assert_eq!(clauses.len(), 2);
+                assert_eq!(clauses[0].2.len(), 1);
+                assert!(matches!(clauses[1].1.expr, ExprKind::Range(..)));
+            }
+            e => panic!("comprehension expected, got {:?}", e),
+        }
+        assert!(matches!(parse("[]")[0].expr, ExprKind::Array(ref v) if v.is_empty()));
+        assert!(matches!(parse("[1, 2,]")[0].expr, ExprKind::Array(ref v) if v.len() == 2));
+    }
+
+    #[test]
+    fn parse_macros() {
+        let ast = parse("macro swap(a, b) {\n let tmp = a\n a = b\n b = tmp\n}\nswap!(tmp, y[0])");
+        // The definition itself leaves nothing behind.
+        assert_eq!(ast.len(), 1);
+        match &ast[0].expr {
+            ExprKind::Expansion(name, block) => {
+                assert_eq!(name, "swap");
+                match &block.expr {
+                    ExprKind::Block(v) => match &v[0].expr {
+                        ExprKind::Let(_, pat, value) => {
+                            assert_eq!(pat.decl, PatternDecl::Ident("tmp$1".into()));
+                            assert_eq!(value.expr, ExprKind::Ident("tmp".into()));
+                        }
+                        e => panic!("let expected, got {:?}", e),
+                    },
+                    e => panic!("block expected, got {:?}", e),
+                }
+            }
+            e => panic!("expansion expected, got {:?}", e),
+        }
+
+        let error = |code: &str| {
+            let mut ast = vec![];
+            Parser::new(Reader::from_string(code), &mut ast)
+                .parse()
+                .unwrap_err()
+        };
+        assert_eq!(
+            error("macro m(a) { a }\nm!(1, 2)").msg,
+            Msg::MacroArgs("m".into(), 1, 2)
+        );
+        let err = error("macro m(a) {\n a +\n}\nm!(1)");
+        assert_eq!(err.pos, Position::new(4, 1));
+        assert!(matches!(err.msg, Msg::InMacro(ref name, _, _) if name == "m"));
+        assert_eq!(
+            error("macro m() { m!() }\nm!()").msg,
+            Msg::MacroRecursion("m".into())
+        );
+    }
+
+    #[test]
+    fn macros_scoped_to_their_block() {
+        let ast = parse("macro m() { 1 }\nfunction f() {\n macro m() { 2 }\n m!()\n}\nm!()");
+        let expanded = |e: &Expr| match &e.expr {
+            ExprKind::Expansion(_, block) => match &block.expr {
+                ExprKind::Block(v) => v[0].expr.clone(),
+                e => panic!("block expected, got {:?}", e),
+            },
+            e => panic!("expansion expected, got {:?}", e),
+        };
+        match &ast[0].expr {
+            ExprKind::Function(_, _, body) => match &body.expr {
+                ExprKind::Block(v) => assert_eq!(expanded(&v[0]), ExprKind::ConstInt(2)),
+                e => panic!("block expected, got {:?}", e),
+            },
+            e => panic!("function expected, got {:?}", e),
+        }
+        assert_eq!(expanded(&ast[1]), ExprKind::ConstInt(1));
+
+        let mut ast = vec![];
+        let mut parser = Parser::new(
+            Reader::from_string("function f() {\n macro m() { 1 }\n m!()\n}"),
+            &mut ast,
+        );
+        parser.parse().unwrap();
+        assert!(parser.macros.is_empty());
+    }
+
+    #[test]
+    fn macro_hygiene_renames_every_binder() {
+        // Expands `m!(x)` for a template using `x` next to its own binder `a`.
+        let expand = |template: &str| {
+            let code = format!("macro m(x) {{\n {}\n}}\nm!(a)", template);
+            let ast = parse(&code);
+            format!("{:?}", ast)
+                .split_whitespace()
+                .collect::<String>()
+                .replace(",)", ")")
+                .replace(",]", "]")
+                .replace(",}", "}")
+        };
+        for template in [
+            "let [a, b] = x",
+            "let {a} = x",
+            "let f = |a| a + x",
+            "let f = function(a) { a + x }",
+            "match x { [a] -> a }",
+            "if let [a] = x { a }",
+            "receive { [:msg, a] -> a + x }",
+            "for a in x { a }",
+            "try { x } catch a { a }",
+        ]
+        .iter()
+        {
+            let ast = expand(template);
+            assert!(ast.contains("a$1"), "{} binds `a`: {}", template, ast);
+            // The argument still refers to the caller's `a`.
+            assert!(ast.contains("Ident(\"a\")"), "{}: {}", template, ast);
+        }
+        // A renamed shorthand field keeps its name.
+        assert!(expand("let {a} = x").contains("Record([(\"a\",Some(Pattern{decl:Ident(\"a$1\")"));
+        assert!(expand("let a = 1\n new { a }").contains("Field(\"a\",Some(Ident(\"a$1\"))"));
+        // Field names are not bindings.
+        let ast = expand("let a = x.a\n new { a: a }");
+        assert!(ast.contains("Access(Ident(\"a\"),\"a\")"), "{}", ast);
+        assert!(ast.contains("Field(\"a\",Some(Ident(\"a$1\"))"), "{}", ast);
+    }
+
+    #[test]
+    fn parse_optional_chains() {
+        let ast = parse("a?.b.m(1)\nx?[0] ?? y");
+        match &ast[0].expr {
+            ExprKind::Optional(chain) => match &chain.expr {
+                ExprKind::Call(callee, _) => match &callee.expr {
+                    ExprKind::Access(object, m) => {
+                        assert_eq!(m, "m");
+                        match &object.expr {
+                            ExprKind::Access(a, b) => {
+                                assert_eq!(b, "b");
+                                assert!(matches!(a.expr, ExprKind::SafeNav(_)));
+                            }
+                            e => panic!("access expected, got {:?}", e),
+                        }
+                    }
+                    e => panic!("access expected, got {:?}", e),
+                },
+                e => panic!("call expected, got {:?}", e),
+            },
+            e => panic!("optional chain expected, got {:?}", e),
+        }
+        match &ast[1].expr {
+            ExprKind::BinOp(lhs, op, _) => {
+                assert_eq!(op, "??");
+                assert!(matches!(lhs.expr, ExprKind::Optional(_)));
+            }
+            e => panic!("?? expected, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parse_nil_coalescing_precedence() {
+        let ast = parse("x = a ?? b == c ?? d");
+        let value = match &ast[0].expr {
+            ExprKind::Assign(_, value) => value,
+            e => panic!("assignment expected, got {:?}", e),
+        };
+        match &value.expr {
+            ExprKind::BinOp(lhs, op, rhs) => {
+                assert_eq!(op, "??");
+                assert!(matches!(&rhs.expr, ExprKind::Ident(d) if d == "d"));
+                match &lhs.expr {
+                    ExprKind::BinOp(a, op, cmp) => {
+                        assert_eq!(op, "??");
+                        assert!(matches!(&a.expr, ExprKind::Ident(a) if a == "a"));
+                        assert!(matches!(&cmp.expr, ExprKind::BinOp(_, op, _) if op == "=="));
+                    }
+                    e => panic!("?? expected, got {:?}", e),
+                }
+            }
+            e => panic!("?? expected, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parse_pipelines() {
+        match &parse("xs |> map(f) |> len == n + 1")[0].expr {
+            ExprKind::BinOp(lhs, op, _) => {
+                assert_eq!(op, "==");
+                match &lhs.expr {
+                    ExprKind::Call(len, args) => {
+                        assert_eq!(len.expr, ExprKind::Ident("len".into()));
+                        assert_eq!(args.len(), 1);
+                        match &args[0].expr {
+                            ExprKind::Call(map, args) => {
+                                assert_eq!(map.expr, ExprKind::Ident("map".into()));
+                                assert_eq!(args[0].expr, ExprKind::Ident("xs".into()));
+                                assert_eq!(args[1].expr, ExprKind::Ident("f".into()));
+                            }
+                            e => panic!("call expected, got {:?}", e),
+                        }
+                    }
+                    e => panic!("call expected, got {:?}", e),
+                }
+            }
+            e => panic!("comparison expected, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parse_compound_assignments() {
+        let ast = parse("i += 1\nthis.nodes[i][1] <<= 2");
+        match &ast[0].expr {
+            ExprKind::CompoundAssign(op, lhs, _) => {
+                assert_eq!(op, "+");
+                assert_eq!(lhs.expr, ExprKind::Ident("i".into()));
+            }
+            e => panic!("compound assignment expected, got {:?}", e),
+        }
+        match &ast[1].expr {
+            ExprKind::CompoundAssign(op, lhs, _) => {
+                assert_eq!(op, "<<");
+                assert!(matches!(lhs.expr, ExprKind::ArrayIndex(..)));
+            }
+            e => panic!("compound assignment expected, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parse_bitwise_precedence() {
+        let ops = |code: &str| match &parse(code)[0].expr {
+            ExprKind::BinOp(l, op, r) => {
+                let op_of = |e: &Expr| match &e.expr {
+                    ExprKind::BinOp(_, op, _) => op.clone(),
+                    _ => String::new(),
+                };
+                (op_of(l), op.clone(), op_of(r))
+            }
+            e => panic!("binary operator expected, got {:?}", e),
+        };
+        let s = String::from;
+        assert_eq!(ops("a | b ^ c"), (s(""), s("|"), s("^")));
+        assert_eq!(ops("a ^ b & c"), (s(""), s("^"), s("&")));
+        assert_eq!(ops("a & b == c"), (s(""), s("&"), s("==")));
+        assert_eq!(ops("a << b + c"), (s(""), s("<<"), s("+")));
+        assert_eq!(ops("a + b >>> c"), (s("+"), s(">>>"), s("")));
+    }
+
+    #[test]
+    fn parse_default_and_rest_params() {
+        match &parse("function f(a, var b = 10 | 1, ...rest) { a }")[0].expr {
+            ExprKind::Function(_, args, _) => {
+                assert_eq!(args[0], Arg::Ident(false, "a".into()));
+                match &args[1] {
+                    Arg::Default(true, name, value) => {
+                        assert_eq!(name, "b");
+                        assert!(value.is_binop());
+                    }
+                    arg => panic!("default expected, got {:?}", arg),
+                }
+                assert_eq!(args[2], Arg::Rest("rest".into()));
+            }
+            e => panic!("function expected, got {:?}", e),
+        }
+        match &parse("|a = 1| a")[0].expr {
+            ExprKind::Lambda(args, _) => match &args[0] {
+                Arg::Default(false, _, value) => assert_eq!(value.expr, ExprKind::ConstInt(1)),
+                arg => panic!("default expected, got {:?}", arg),
+            },
+            e => panic!("lambda expected, got {:?}", e),
+        }
+        for code in ["function f(...a, b) { a }", "function f(a = 1, b) { a }"].iter() {
+            let mut ast = vec![];
+            assert!(Parser::new(Reader::from_string(code), &mut ast)
+                .parse()
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn parse_spread() {
+        let ast =
+            parse("f(...args, x)\nlet a = [...a, x, ...b]\nlet o = new { ...base, extra: 1 }");
+        let is_spread = |e: &Expr| matches!(e.expr, ExprKind::Spread(_));
+        match &ast[0].expr {
+            ExprKind::Call(_, args) => {
+                assert!(is_spread(&args[0]));
+                assert!(!is_spread(&args[1]));
+            }
+            e => panic!("call expected, got {:?}", e),
+        }
+        match &ast[1].expr {
+            ExprKind::Let(_, _, value) => match &value.expr {
+                ExprKind::Array(items) => {
+                    let spreads: Vec<bool> = items.iter().map(|e| is_spread(e)).collect();
+                    assert_eq!(spreads, vec![true, false, true]);
+                }
+                e => panic!("array expected, got {:?}", e),
+            },
+            e => panic!("let expected, got {:?}", e),
+        }
+        match &ast[2].expr {
+            ExprKind::Let(_, _, value) => match &value.expr {
+                ExprKind::NewObject(fields) => {
+                    assert!(matches!(fields[0], ObjectField::Spread(_)));
+                    assert!(matches!(&fields[1], ObjectField::Field(name, _) if name == "extra"));
+                }
+                e => panic!("object expected, got {:?}", e),
+            },
+            e => panic!("let expected, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parse_keyword_args() {
+        match &parse("f(a, x: 1, y: b + 1)")[0].expr {
+            ExprKind::Call(_, args) => {
+                assert_eq!(args.len(), 2);
+                assert_eq!(args[0].expr, ExprKind::Ident("a".into()));
+                match &args[1].expr {
+                    ExprKind::KeywordArgs(fields) => {
+                        let names: Vec<&str> = fields.iter().map(|(n, _)| n.as_str()).collect();
+                        assert_eq!(names, vec!["x", "y"]);
+                    }
+                    e => panic!("keyword arguments expected, got {:?}", e),
+                }
+            }
+            e => panic!("call expected, got {:?}", e),
+        }
+        for code in ["f(x: 1, a)", "f(x: 1, x: 2)"].iter() {
+            let mut ast = vec![];
+            assert!(Parser::new(Reader::from_string(code), &mut ast)
+                .parse()
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn parse_nested_arg_patterns() {
+        match &parse("function f({pos: [x, y], name}) { x }")[0].expr {
+            ExprKind::Function(_, args, _) => match &args[0] {
+                Arg::Record(fields) => {
+                    assert_eq!(fields[0].0, "pos");
+                    assert_eq!(
+                        strip(fields[0].1.as_ref().unwrap().decl.clone()),
+                        PatternDecl::Array(vec![
+                            pat(PatternDecl::Ident("x".into())),
+                            pat(PatternDecl::Ident("y".into()))
+                        ])
+                    );
+                    assert_eq!(fields[1], ("name".to_owned(), None));
+                }
+                arg => panic!("record argument expected, got {:?}", arg),
+            },
+            e => panic!("function expected, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parse_destructuring_assignments() {
+        let ast = parse("[v0, v1] = [v1, v0]\n{x, y: this.y} = point\n{x} = point\n{ x }");
+        assert_eq!(ast.len(), 4);
+        match &ast[0].expr {
+            ExprKind::Assign(lhs, rhs) => {
+                assert!(matches!(lhs.expr, ExprKind::Array(_)));
+                assert!(matches!(rhs.expr, ExprKind::Array(_)));
+            }
+            e => panic!("assignment expected, got {:?}", e),
+        }
+        match &ast[1].expr {
+            ExprKind::Assign(lhs, _) => match &lhs.expr {
+                ExprKind::NewObject(fields) => {
+                    assert_eq!(fields[0], ObjectField::Field("x".to_owned(), None));
+                    match &fields[1] {
+                        ObjectField::Field(_, Some(value)) => assert!(value.is_access()),
+                        field => panic!("field expected, got {:?}", field),
+                    }
+                }
+                e => panic!("record target expected, got {:?}", e),
+            },
+            e => panic!("assignment expected, got {:?}", e),
+        }
+        assert!(matches!(ast[2].expr, ExprKind::Assign(..)));
+        assert!(matches!(ast[3].expr, ExprKind::Block(_)));
+    }
+
+    #[test]
+    fn parse_atoms_at_statement_start() {
+        match &parse("function f() { cleanup()\n :stop }")[0].expr {
+            ExprKind::Function(_, _, body) => match &body.expr {
+                ExprKind::Block(exprs) => {
+                    assert_eq!(exprs[1].expr, ExprKind::ConstAtom("stop".into()))
+                }
+                e => panic!("block expected, got {:?}", e),
+            },
+            e => panic!("function expected, got {:?}", e),
+        }
+        let ast = parse("let a = b\n:ok");
+        assert_eq!(ast.len(), 2);
+        assert_eq!(ast[1].expr, ExprKind::ConstAtom("ok".into()));
+    }
+
+    #[test]
+    fn parse_labels_lexed_as_atoms() {
+        for code in ["new {x :y}", "{x:y} = p", "let {x:y} = p"].iter() {
+            let ast = format!("{:?}", parse(code));
+            assert!(!ast.contains("ConstAtom"), "{}: {}", code, ast);
+        }
+        match &parse("f(x :y)")[0].expr {
+            ExprKind::Call(_, args) => match &args[0].expr {
+                ExprKind::KeywordArgs(fields) => {
+                    assert_eq!(fields[0].0, "x");
+                    assert_eq!(fields[0].1.expr, ExprKind::Ident("y".into()));
+                }
+                e => panic!("keyword arguments expected, got {:?}", e),
+            },
+            e => panic!("call expected, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parse_cons_patterns() {
+        let arms = arm_patterns("match xs { [] -> 0, h :: _ :: t -> 1 }");
+        assert_eq!(arms[0], PatternDecl::EmptyList);
+        assert_eq!(
+            strip(arms[1].clone()),
+            PatternDecl::Cons(
+                pat(PatternDecl::Ident("h".into())),
+                pat(PatternDecl::Cons(
+                    pat(PatternDecl::Pass),
+                    pat(PatternDecl::Ident("t".into()))
+                ))
+            )
+        );
+    }
+}
@@ -42,6 +42,7 @@ pub enum TokenKind {
     LitInt(String, IntBase, IntSuffix),
     LitFloat(String),
     Identifier(String),
+    Atom(String),
     End,
 
     LQuote,
@@ -68,6 +69,19 @@ pub enum TokenKind {
     False,
     Nil,
     Throw,
+    Try,
+    Catch,
+    Finally,
+    Break,
+    Continue,
+    Defer,
+    Yield,
+    Async,
+    Await,
+    Receive,
+    Actor,
+    Parallel,
+    Macro,
     Do,
 
     Type,
@@ -93,11 +107,18 @@ pub enum TokenKind {
     Semicolon,
     Dot,
     DotDot,
+    DotDotEq,
+    DotDotDot,
+    QuestionDot,
+    QuestionBracket,
+    QuestionQuestion,
     Colon,
     Sep, // ::
     Arrow,
     Tilde,
+    At,
     BitOr,
+    Pipe,
     BitAnd,
     Caret,
     And,
@@ -112,7 +133,20 @@ pub enum TokenKind {
     Ge,
 
     GtGt,
+    GtGtGt,
     LtLt,
+
+    AddEq,
+    SubEq,
+    MulEq,
+    DivEq,
+    ModEq,
+    LtLtEq,
+    GtGtEq,
+    GtGtGtEq,
+    BitAndEq,
+    BitOrEq,
+    CaretEq,
 }
 
 impl TokenKind {
@@ -130,6 +164,7 @@ impl TokenKind {
             TokenKind::LitFloat(_) => "float number",
 
             TokenKind::Identifier(_) => "identifier",
+            TokenKind::Atom(_) => "atom",
             TokenKind::End => "<<EOF>>",
 
             TokenKind::LQuote => "<",
@@ -154,6 +189,19 @@ impl TokenKind {
             TokenKind::False => "false",
             TokenKind::Nil => "nil",
             TokenKind::Throw => "throw",
+            TokenKind::Try => "try",
+            TokenKind::Catch => "catch",
+            TokenKind::Finally => "finally",
+            TokenKind::Break => "break",
+            TokenKind::Continue => "continue",
+            TokenKind::Defer => "defer",
+            TokenKind::Yield => "yield",
+            TokenKind::Async => "async",
+            TokenKind::Await => "await",
+            TokenKind::Receive => "receive",
+            TokenKind::Actor => "actor",
+            TokenKind::Parallel => "parallel",
+            TokenKind::Macro => "macro",
             TokenKind::Match => "match",
             TokenKind::Do => "do",
             TokenKind::Type => "type",
@@ -179,11 +227,18 @@ impl TokenKind {
             TokenKind::Semicolon => ";",
             TokenKind::Dot => ".",
             TokenKind::DotDot => "..",
+            TokenKind::DotDotEq => "..=",
+            TokenKind::DotDotDot => "...",
+            TokenKind::QuestionDot => "?.",
+            TokenKind::QuestionBracket => "?[",
+            TokenKind::QuestionQuestion => "??",
             TokenKind::Colon => ":",
             TokenKind::Sep => "::",
             TokenKind::Arrow => "=>",
             TokenKind::Tilde => "~",
+            TokenKind::At => "@",
             TokenKind::BitOr => "|",
+            TokenKind::Pipe => "|>",
             TokenKind::BitAnd => "&",
             TokenKind::Caret => "^",
             TokenKind::And => "&&",
@@ -197,7 +252,20 @@ impl TokenKind {
             TokenKind::Ge => ">=",
 
             TokenKind::GtGt => ">>",
+            TokenKind::GtGtGt => ">>>",
             TokenKind::LtLt => "<<",
+
+            TokenKind::AddEq => "+=",
+            TokenKind::SubEq => "-=",
+            TokenKind::MulEq => "*=",
+            TokenKind::DivEq => "/=",
+            TokenKind::ModEq => "%=",
+            TokenKind::LtLtEq => "<<=",
+            TokenKind::GtGtEq => ">>=",
+            TokenKind::GtGtGtEq => ">>>=",
+            TokenKind::BitAndEq => "&=",
+            TokenKind::BitOrEq => "|=",
+            TokenKind::CaretEq => "^=",
         }
     }
 }
@@ -245,6 +313,7 @@ impl Token {
 
             TokenKind::String(ref val) => format!("\"{}\"", &val),
             TokenKind::Identifier(ref val) => val.clone(),
+            TokenKind::Atom(ref val) => format!(":{}", val),
 
             _ => self.kind.name().into(),
         }